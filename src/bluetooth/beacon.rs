@@ -0,0 +1,381 @@
+use std::time::Duration;
+
+use opentelemetry::{KeyValue, metrics::Counter};
+use uuid::Uuid;
+
+use super::rssi::PathLossModel;
+use crate::metrics::{self, Gauge};
+
+pub(crate) const DRIVER: &str = "beacon";
+
+pub(crate) const APPLE_COMPANY_ID: u16 = 0x004c;
+pub(crate) const EDDYSTONE_SERVICE_ID: Uuid =
+    Uuid::from_u128(0x0000feaa_0000_1000_8000_00805f9b34fb);
+
+const IBEACON_TYPE: u8 = 0x02;
+const IBEACON_LENGTH: u8 = 0x15;
+
+const EDDYSTONE_UID: u8 = 0x00;
+const EDDYSTONE_URL: u8 = 0x10;
+const EDDYSTONE_TLM: u8 = 0x20;
+
+/// Eddystone advertises its power at 0m while the distance estimation expects it at 1m
+const EDDYSTONE_POWER_LOSS_AT_1M: i8 = 41;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct IBeacon {
+    pub uuid: Uuid,
+    pub major: u16,
+    pub minor: u16,
+    /// Calibrated RSSI at 1 meter
+    pub tx_power: i8,
+}
+
+impl IBeacon {
+    /// Parses the manufacturer data advertised with the Apple company identifier
+    pub(crate) fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < 23 || data[0] != IBEACON_TYPE || data[1] != IBEACON_LENGTH {
+            return None;
+        }
+        let uuid = Uuid::from_slice(&data[2..18]).ok()?;
+        Some(Self {
+            uuid,
+            major: u16::from_be_bytes([data[18], data[19]]),
+            minor: u16::from_be_bytes([data[20], data[21]]),
+            tx_power: data[22] as i8,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Eddystone {
+    Uid {
+        /// Calibrated power at 0 meter
+        tx_power: i8,
+        namespace: [u8; 10],
+        instance: [u8; 6],
+    },
+    Url {
+        /// Calibrated power at 0 meter
+        tx_power: i8,
+        url: String,
+    },
+    Tlm(EddystoneTlm),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct EddystoneTlm {
+    /// Battery voltage in millivolts, 0 when not supported
+    pub battery_voltage: u16,
+    /// Temperature in degree celsius, when supported
+    pub temperature: Option<f64>,
+    pub advertising_count: u32,
+    pub uptime: Duration,
+}
+
+impl Eddystone {
+    /// Parses the service data advertised on the Eddystone service
+    pub(crate) fn parse(data: &[u8]) -> Option<Self> {
+        match *data.first()? {
+            EDDYSTONE_UID if data.len() >= 18 => Some(Self::Uid {
+                tx_power: data[1] as i8,
+                namespace: data[2..12].try_into().ok()?,
+                instance: data[12..18].try_into().ok()?,
+            }),
+            EDDYSTONE_URL if data.len() >= 3 => Some(Self::Url {
+                tx_power: data[1] as i8,
+                url: decode_url(data[2], &data[3..])?,
+            }),
+            // only the unencrypted version is supported
+            EDDYSTONE_TLM if data.len() >= 14 && data[1] == 0x00 => {
+                let temperature = i16::from_be_bytes([data[4], data[5]]);
+                Some(Self::Tlm(EddystoneTlm {
+                    battery_voltage: u16::from_be_bytes([data[2], data[3]]),
+                    temperature: (temperature != i16::MIN).then(|| temperature as f64 / 256.0),
+                    advertising_count: u32::from_be_bytes([data[6], data[7], data[8], data[9]]),
+                    uptime: Duration::from_millis(
+                        u32::from_be_bytes([data[10], data[11], data[12], data[13]]) as u64 * 100,
+                    ),
+                }))
+            }
+            _ => None,
+        }
+    }
+
+    /// Calibrated power at 1 meter
+    fn tx_power(&self) -> Option<i8> {
+        match self {
+            Self::Uid { tx_power, .. } | Self::Url { tx_power, .. } => {
+                Some(tx_power.saturating_sub(EDDYSTONE_POWER_LOSS_AT_1M))
+            }
+            Self::Tlm(_) => None,
+        }
+    }
+}
+
+fn decode_url(scheme: u8, encoded: &[u8]) -> Option<String> {
+    let mut url = String::from(match scheme {
+        0x00 => "http://www.",
+        0x01 => "https://www.",
+        0x02 => "http://",
+        0x03 => "https://",
+        _ => return None,
+    });
+    for byte in encoded {
+        match byte {
+            0x00 => url.push_str(".com/"),
+            0x01 => url.push_str(".org/"),
+            0x02 => url.push_str(".edu/"),
+            0x03 => url.push_str(".net/"),
+            0x04 => url.push_str(".info/"),
+            0x05 => url.push_str(".biz/"),
+            0x06 => url.push_str(".gov/"),
+            0x07 => url.push_str(".com"),
+            0x08 => url.push_str(".org"),
+            0x09 => url.push_str(".edu"),
+            0x0a => url.push_str(".net"),
+            0x0b => url.push_str(".info"),
+            0x0c => url.push_str(".biz"),
+            0x0d => url.push_str(".gov"),
+            0x21..=0x7e => url.push(*byte as char),
+            _ => return None,
+        }
+    }
+    Some(url)
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+#[derive(Debug)]
+pub(crate) struct BeaconCollector {
+    path_loss: PathLossModel,
+    frames: Counter<u64>,
+    distance: Gauge<f64>,
+    battery_voltage: Gauge<f64>,
+    temperature: Gauge<f64>,
+    advertising_count: Gauge<u64>,
+    uptime: Gauge<f64>,
+}
+
+impl BeaconCollector {
+    pub(crate) fn new(path_loss: PathLossModel) -> Self {
+        let meter = opentelemetry::global::meter(DRIVER);
+
+        Self {
+            path_loss,
+            frames: metrics::BEACON_FRAMES.u64_counter(&meter),
+            distance: metrics::BEACON_DISTANCE.f64_gauge(&meter),
+            battery_voltage: metrics::BEACON_BATTERY_VOLTAGE.f64_gauge(&meter),
+            temperature: metrics::BEACON_TEMPERATURE.f64_gauge(&meter),
+            advertising_count: metrics::BEACON_ADVERTISING_COUNT.u64_gauge(&meter),
+            uptime: metrics::BEACON_UPTIME.f64_gauge(&meter),
+        }
+    }
+
+    pub async fn collect(
+        &self,
+        device: &bluer::Device,
+        attributes: &[opentelemetry::KeyValue],
+    ) -> anyhow::Result<bool> {
+        let ibeacon = device.manufacturer_data().await?.and_then(|data| {
+            data.get(&APPLE_COMPANY_ID)
+                .and_then(|value| IBeacon::parse(value))
+        });
+        let eddystone = device.service_data().await?.and_then(|data| {
+            data.get(&EDDYSTONE_SERVICE_ID)
+                .and_then(|value| Eddystone::parse(value))
+        });
+        if ibeacon.is_none() && eddystone.is_none() {
+            return Ok(false);
+        }
+
+        let rssi = device.rssi().await.ok().flatten();
+
+        if let Some(beacon) = ibeacon {
+            let mut attributes = attributes.to_vec();
+            attributes.push(KeyValue::new("beacon.kind", "ibeacon"));
+            attributes.push(KeyValue::new("beacon.uuid", beacon.uuid.to_string()));
+            attributes.push(KeyValue::new("beacon.major", beacon.major as i64));
+            attributes.push(KeyValue::new("beacon.minor", beacon.minor as i64));
+            self.frames.add(1, &attributes);
+            if let Some(rssi) = rssi {
+                let distance = self
+                    .path_loss
+                    .distance_with(beacon.tx_power as f64, rssi as f64);
+                self.distance.record(distance, &attributes);
+            }
+        }
+
+        if let Some(frame) = eddystone {
+            self.collect_eddystone(frame, rssi, attributes);
+        }
+
+        Ok(true)
+    }
+
+    fn collect_eddystone(&self, frame: Eddystone, rssi: Option<i16>, attributes: &[KeyValue]) {
+        let mut attributes = attributes.to_vec();
+        match frame {
+            Eddystone::Uid {
+                namespace,
+                instance,
+                ..
+            } => {
+                attributes.push(KeyValue::new("beacon.kind", "eddystone-uid"));
+                attributes.push(KeyValue::new("beacon.namespace", hex(&namespace)));
+                attributes.push(KeyValue::new("beacon.instance", hex(&instance)));
+            }
+            Eddystone::Url { ref url, .. } => {
+                attributes.push(KeyValue::new("beacon.kind", "eddystone-url"));
+                attributes.push(KeyValue::new("beacon.url", url.clone()));
+            }
+            Eddystone::Tlm(ref tlm) => {
+                attributes.push(KeyValue::new("beacon.kind", "eddystone-tlm"));
+                if tlm.battery_voltage > 0 {
+                    self.battery_voltage
+                        .record(tlm.battery_voltage as f64 / 1000.0, &attributes);
+                }
+                if let Some(value) = tlm.temperature {
+                    self.temperature.record(value, &attributes);
+                }
+                self.advertising_count
+                    .record(tlm.advertising_count as u64, &attributes);
+                self.uptime.record(tlm.uptime.as_secs_f64(), &attributes);
+            }
+        }
+        self.frames.add(1, &attributes);
+        if let (Some(tx_power), Some(rssi)) = (frame.tx_power(), rssi) {
+            let distance = self.path_loss.distance_with(tx_power as f64, rssi as f64);
+            self.distance.record(distance, &attributes);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IBEACON: [u8; 23] = [
+        0x02, 0x15, // type and length
+        0xe2, 0xc5, 0x6d, 0xb5, 0xdf, 0xfb, 0x48, 0xd2, 0xb0, 0x60, 0xd0, 0xf5, 0xa7, 0x10, 0x96,
+        0xe0, // uuid
+        0x00, 0x01, // major
+        0x00, 0x02, // minor
+        0xc5, // tx power
+    ];
+
+    const TLM: [u8; 14] = [
+        0x20, 0x00, // type and version
+        0x0b, 0xb8, // battery voltage
+        0x19, 0x80, // temperature
+        0x00, 0x00, 0x00, 0x0a, // advertising count
+        0x00, 0x00, 0x00, 0x64, // uptime
+    ];
+
+    #[test]
+    fn parses_ibeacon() {
+        assert_eq!(
+            IBeacon::parse(&IBEACON),
+            Some(IBeacon {
+                uuid: Uuid::from_u128(0xe2c56db5_dffb_48d2_b060_d0f5a71096e0),
+                major: 1,
+                minor: 2,
+                tx_power: -59,
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_ibeacon() {
+        assert_eq!(IBeacon::parse(&IBEACON[..22]), None);
+        assert_eq!(IBeacon::parse(&[]), None);
+        let mut data = IBEACON;
+        data[1] = 0x14;
+        assert_eq!(IBeacon::parse(&data), None);
+    }
+
+    #[test]
+    fn parses_eddystone_uid() {
+        let mut data = vec![0x00, 0xe7];
+        data.extend(0..10);
+        data.extend(10..16);
+        let frame = Eddystone::parse(&data).unwrap();
+        assert_eq!(
+            frame,
+            Eddystone::Uid {
+                tx_power: -25,
+                namespace: [0, 1, 2, 3, 4, 5, 6, 7, 8, 9],
+                instance: [10, 11, 12, 13, 14, 15],
+            }
+        );
+        assert_eq!(frame.tx_power(), Some(-66));
+        assert_eq!(Eddystone::parse(&data[..17]), None);
+    }
+
+    #[test]
+    fn parses_eddystone_url() {
+        let mut data = vec![0x10, 0xf8, 0x00];
+        data.extend(b"example");
+        data.push(0x07);
+        assert_eq!(
+            Eddystone::parse(&data),
+            Some(Eddystone::Url {
+                tx_power: -8,
+                url: "http://www.example.com".into(),
+            })
+        );
+        let mut data = vec![0x10, 0xf8, 0x03];
+        data.extend(b"example");
+        data.push(0x00);
+        data.extend(b"docs");
+        assert_eq!(
+            decode_url(data[2], &data[3..]).as_deref(),
+            Some("https://example.com/docs")
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_eddystone_url() {
+        // unknown scheme
+        assert_eq!(Eddystone::parse(&[0x10, 0xf8, 0x04, b'a']), None);
+        // reserved expansion code
+        assert_eq!(Eddystone::parse(&[0x10, 0xf8, 0x02, b'a', 0x0e]), None);
+        assert_eq!(Eddystone::parse(&[0x10, 0xf8]), None);
+    }
+
+    #[test]
+    fn parses_eddystone_tlm() {
+        assert_eq!(
+            Eddystone::parse(&TLM),
+            Some(Eddystone::Tlm(EddystoneTlm {
+                battery_voltage: 3000,
+                temperature: Some(25.5),
+                advertising_count: 10,
+                uptime: Duration::from_secs(10),
+            }))
+        );
+    }
+
+    #[test]
+    fn parses_eddystone_tlm_without_temperature() {
+        let mut data = TLM;
+        data[4..6].copy_from_slice(&[0x80, 0x00]);
+        let Some(Eddystone::Tlm(tlm)) = Eddystone::parse(&data) else {
+            panic!("tlm frame expected");
+        };
+        assert_eq!(tlm.temperature, None);
+    }
+
+    #[test]
+    fn rejects_invalid_eddystone_tlm() {
+        assert_eq!(Eddystone::parse(&TLM[..13]), None);
+        // encrypted
+        let mut data = TLM;
+        data[1] = 0x01;
+        assert_eq!(Eddystone::parse(&data), None);
+        assert_eq!(Eddystone::parse(&[]), None);
+        assert_eq!(Eddystone::parse(&[0x30]), None);
+    }
+}
@@ -0,0 +1,24 @@
+//! Company identifiers assigned by the Bluetooth SIG, used as keys of the manufacturer data.
+//!
+//! The table is generated by bluer from the assigned numbers, through the Bluetooth numbers
+//! database maintained by Nordic Semiconductor.
+
+use std::num::ParseIntError;
+
+pub(crate) fn company_name(id: u16) -> Option<String> {
+    bluer::id::Manufacturer::try_from(id)
+        .ok()
+        .map(|company| company.to_string())
+}
+
+/// Parses an identifier written in decimal or in hexadecimal like `0x004c`
+pub(crate) fn parse_company_id(value: &str) -> Result<u16, ParseIntError> {
+    let value = value.trim();
+    match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => value.parse(),
+    }
+}
@@ -0,0 +1,182 @@
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use anyhow::Context;
+
+use crate::config::Source;
+
+/// Time between two connections to a device that needs polling
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(60 * 60 * 2); // 2h
+
+/// Settings of individual devices, keyed by address or by resolved identity
+#[derive(Debug, Default)]
+pub(crate) struct DeviceSettings {
+    aliases: HashMap<String, String>,
+    poll_intervals: HashMap<String, Duration>,
+    /// When not empty, only these devices are sent to the drivers
+    allow: HashSet<String>,
+    deny: HashSet<String>,
+}
+
+impl crate::Configurable for DeviceSettings {
+    fn from_source(source: &Source) -> Self {
+        let aliases = source
+            .parse_list::<Entry<String>>("BLUETOOTH_DEVICE_ALIASES", "bluetooth.aliases")
+            .unwrap_or_default()
+            .into_iter()
+            .map(|entry| (entry.device.0, entry.value))
+            .collect();
+        let poll_intervals = source
+            .parse_list::<Entry<u64>>("BLUETOOTH_POLL_INTERVALS", "bluetooth.poll_intervals")
+            .unwrap_or_default()
+            .into_iter()
+            .map(|entry| (entry.device.0, Duration::from_secs(entry.value)))
+            .collect();
+        let devices = |env: &str, key: &str| -> HashSet<String> {
+            source
+                .parse_list::<DeviceKey>(env, key)
+                .unwrap_or_default()
+                .into_iter()
+                .map(|device| device.0)
+                .collect()
+        };
+        Self {
+            aliases,
+            poll_intervals,
+            allow: devices("BLUETOOTH_ALLOW_DEVICES", "bluetooth.allow"),
+            deny: devices("BLUETOOTH_DENY_DEVICES", "bluetooth.deny"),
+        }
+    }
+}
+
+impl DeviceSettings {
+    /// Name given to the device, the identity takes precedence over the address
+    pub(crate) fn alias(&self, address: bluer::Address, identity: Option<&str>) -> Option<&str> {
+        identity
+            .and_then(|identity| self.aliases.get(identity))
+            .or_else(|| self.aliases.get(&address.to_string()))
+            .map(String::as_str)
+    }
+
+    /// Whether the device passes the allow and deny lists
+    pub(crate) fn is_allowed(&self, address: bluer::Address, identity: Option<&str>) -> bool {
+        let address = address.to_string();
+        let listed = |set: &HashSet<String>| {
+            set.contains(&address) || identity.is_some_and(|identity| set.contains(identity))
+        };
+        (self.allow.is_empty() || listed(&self.allow)) && !listed(&self.deny)
+    }
+
+    /// Time between two connections to the device, the identity takes precedence over the
+    /// address
+    pub(crate) fn poll_interval(
+        &self,
+        address: bluer::Address,
+        identity: Option<&str>,
+    ) -> Duration {
+        identity
+            .and_then(|identity| self.poll_intervals.get(identity))
+            .or_else(|| self.poll_intervals.get(&address.to_string()))
+            .copied()
+            .unwrap_or(DEFAULT_POLL_INTERVAL)
+    }
+}
+
+/// Address or resolved identity of a device, addresses are kept in their canonical form
+struct DeviceKey(String);
+
+impl FromStr for DeviceKey {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.trim();
+        if value.is_empty() {
+            anyhow::bail!("expected an address or an identity");
+        }
+        // identities are plain names, anything else must be a valid address
+        if value.contains(':') {
+            let address = bluer::Address::from_str(value)
+                .map_err(|_| anyhow::anyhow!("malformed address {value:?}"))?;
+            return Ok(Self(address.to_string()));
+        }
+        Ok(Self(value.to_string()))
+    }
+}
+
+/// Item like `<address or identity>=<value>`
+struct Entry<T> {
+    device: DeviceKey,
+    value: T,
+}
+
+impl<T> FromStr for Entry<T>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (device, value) = value
+            .split_once('=')
+            .context("expected an entry like <device>=<value>")?;
+        let device = device.parse::<DeviceKey>()?;
+        let value = value
+            .trim()
+            .parse::<T>()
+            .map_err(|err| anyhow::anyhow!("invalid value for {:?}: {err}", device.0))?;
+        Ok(Self { device, value })
+    }
+}
+
+/// Device settings that can be replaced while running
+#[derive(Clone, Debug)]
+pub(crate) struct SharedDeviceSettings(Arc<RwLock<Arc<DeviceSettings>>>);
+
+impl SharedDeviceSettings {
+    pub(crate) fn new(settings: DeviceSettings) -> Self {
+        Self(Arc::new(RwLock::new(Arc::new(settings))))
+    }
+
+    pub(crate) fn current(&self) -> Arc<DeviceSettings> {
+        self.0
+            .read()
+            .expect("device settings lock poisoned")
+            .clone()
+    }
+
+    pub(crate) fn replace(&self, settings: DeviceSettings) {
+        *self.0.write().expect("device settings lock poisoned") = Arc::new(settings);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn looks_up_the_poll_interval_by_identity_first() {
+        let address = bluer::Address::from_str("A4:C1:38:00:00:01").unwrap();
+        let settings = DeviceSettings {
+            poll_intervals: HashMap::from([
+                ("phone".to_string(), Duration::from_secs(60)),
+                (address.to_string(), Duration::from_secs(120)),
+            ]),
+            ..Default::default()
+        };
+        assert_eq!(
+            settings.poll_interval(address, Some("phone")),
+            Duration::from_secs(60)
+        );
+        assert_eq!(
+            settings.poll_interval(address, Some("tablet")),
+            Duration::from_secs(120)
+        );
+        let other = bluer::Address::from_str("A4:C1:38:00:00:02").unwrap();
+        assert_eq!(settings.poll_interval(other, None), DEFAULT_POLL_INTERVAL);
+    }
+}
@@ -0,0 +1,124 @@
+use std::{fmt::Debug, str::FromStr};
+
+use aes::{
+    Aes128,
+    cipher::{BlockEncrypt, KeyInit, generic_array::GenericArray},
+};
+use anyhow::Context;
+
+/// Identity Resolving Key of a logical device, as shared during pairing
+#[derive(Clone)]
+pub(crate) struct IdentityKey {
+    name: String,
+    cipher: Aes128,
+}
+
+impl Debug for IdentityKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("IdentityKey")
+            .field("name", &self.name)
+            .finish_non_exhaustive()
+    }
+}
+
+impl FromStr for IdentityKey {
+    type Err = anyhow::Error;
+
+    /// Parses a key like `<name>=<irk>` where the IRK is written in hexadecimal,
+    /// most significant byte first.
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (name, key) = value
+            .split_once('=')
+            .context("expected a key like <name>=<irk>")?;
+        let key = key.trim();
+        if key.len() != 32 || !key.is_ascii() {
+            anyhow::bail!("identity key for {name:?} should be 32 hexadecimal characters");
+        }
+        let mut bytes = [0u8; 16];
+        for (index, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&key[index * 2..index * 2 + 2], 16)
+                .with_context(|| format!("invalid identity key for {name:?}"))?;
+        }
+        Ok(Self {
+            name: name.trim().to_string(),
+            cipher: Aes128::new(&GenericArray::from(bytes)),
+        })
+    }
+}
+
+impl IdentityKey {
+    /// Random address hash function `ah` as defined in the Core specification,
+    /// Vol 3, Part H, 2.2.2.
+    fn ah(&self, prand: [u8; 3]) -> [u8; 3] {
+        let mut block = GenericArray::from([0u8; 16]);
+        block[13..].copy_from_slice(&prand);
+        self.cipher.encrypt_block(&mut block);
+        [block[13], block[14], block[15]]
+    }
+
+    fn resolves(&self, address: bluer::Address) -> bool {
+        let prand = [address.0[0], address.0[1], address.0[2]];
+        self.ah(prand) == [address.0[3], address.0[4], address.0[5]]
+    }
+}
+
+/// Maps resolvable private addresses back to the logical device that emitted them.
+#[derive(Debug, Default)]
+pub(crate) struct IdentityResolver {
+    keys: Vec<IdentityKey>,
+}
+
+impl IdentityResolver {
+    pub(crate) fn new(keys: Vec<IdentityKey>) -> Self {
+        Self { keys }
+    }
+
+    pub(crate) fn resolve(&self, address: bluer::Address) -> Option<&str> {
+        // resolvable private addresses have their two most significant bits set to 0b01
+        if address.0[0] >> 6 != 0b01 {
+            return None;
+        }
+        self.keys
+            .iter()
+            .find(|key| key.resolves(address))
+            .map(|key| key.name.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // sample data from the Core specification, Vol 3, Part H, D.7
+    const SAMPLE_KEY: &str = "phone=ec0234a357c8ad05341010a60a397d9b";
+
+    #[test]
+    fn ah_matches_the_specification_sample() {
+        let key = IdentityKey::from_str(SAMPLE_KEY).unwrap();
+        assert_eq!(key.ah([0x70, 0x81, 0x94]), [0x0d, 0xfb, 0xaa]);
+    }
+
+    #[test]
+    fn resolves_the_specification_sample_address() {
+        let resolver = IdentityResolver::new(vec![IdentityKey::from_str(SAMPLE_KEY).unwrap()]);
+        let address = bluer::Address::from_str("70:81:94:0D:FB:AA").unwrap();
+        assert_eq!(resolver.resolve(address), Some("phone"));
+    }
+
+    #[test]
+    fn ignores_other_addresses() {
+        let resolver = IdentityResolver::new(vec![IdentityKey::from_str(SAMPLE_KEY).unwrap()]);
+        // same hash, but not a resolvable private address
+        let address = bluer::Address::from_str("F0:81:94:0D:FB:AA").unwrap();
+        assert_eq!(resolver.resolve(address), None);
+        let address = bluer::Address::from_str("70:81:94:0D:FB:AB").unwrap();
+        assert_eq!(resolver.resolve(address), None);
+    }
+
+    #[test]
+    fn rejects_invalid_keys() {
+        assert!(IdentityKey::from_str("ec0234a357c8ad05341010a60a397d9b").is_err());
+        assert!(IdentityKey::from_str("phone=ec0234").is_err());
+        assert!(IdentityKey::from_str("phone=zz0234a357c8ad05341010a60a397d9b").is_err());
+    }
+}
@@ -0,0 +1,285 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use bluer::DeviceProperty;
+use serde::Deserialize;
+
+use super::company::{company_name, parse_company_id};
+
+/// Devices that haven't been seen for that long are removed from the inventory
+const RETENTION: Duration = Duration::from_secs(60 * 60 * 24); // 24h
+
+#[derive(Debug, Default)]
+pub(crate) struct InventoryConfig {
+    path: Option<PathBuf>,
+}
+
+impl crate::Configurable for InventoryConfig {
+    fn from_source(source: &crate::config::Source) -> Self {
+        Self {
+            path: source.parse("BLUETOOTH_INVENTORY_PATH", "bluetooth.inventory.path"),
+        }
+    }
+}
+
+impl InventoryConfig {
+    pub(crate) fn build(&self) -> Arc<Inventory> {
+        Arc::new(Inventory {
+            path: self.path.clone(),
+            devices: Default::default(),
+        })
+    }
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+pub(crate) struct Manufacturer {
+    pub id: u16,
+    pub name: Option<String>,
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+pub(crate) struct InventoryEntry {
+    pub address: String,
+    pub address_type: Option<String>,
+    pub identity: Option<String>,
+    pub name: Option<String>,
+    pub icon: Option<String>,
+    pub appearance: Option<u16>,
+    pub manufacturers: Vec<Manufacturer>,
+    pub services: Vec<String>,
+    pub driver: Option<&'static str>,
+    /// Unix timestamp in seconds
+    pub first_seen: u64,
+    /// Unix timestamp in seconds
+    pub last_seen: u64,
+    pub advertisements: u64,
+}
+
+impl InventoryEntry {
+    fn new(address: bluer::Address, now: u64) -> Self {
+        Self {
+            address: address.to_string(),
+            address_type: None,
+            identity: None,
+            name: None,
+            icon: None,
+            appearance: None,
+            manufacturers: Vec::new(),
+            services: Vec::new(),
+            driver: None,
+            first_seen: now,
+            last_seen: now,
+            advertisements: 0,
+        }
+    }
+
+    fn apply(&mut self, property: DeviceProperty) {
+        match property {
+            DeviceProperty::AddressType(value) => self.address_type = Some(value.to_string()),
+            DeviceProperty::Name(value) => self.name = Some(value),
+            DeviceProperty::Icon(value) => self.icon = Some(value),
+            DeviceProperty::Appearance(value) => self.appearance = Some(value),
+            DeviceProperty::ManufacturerData(data) => {
+                let mut ids = data.into_keys().collect::<Vec<_>>();
+                ids.sort_unstable();
+                self.manufacturers = ids
+                    .into_iter()
+                    .map(|id| Manufacturer {
+                        id,
+                        name: company_name(id),
+                    })
+                    .collect();
+            }
+            DeviceProperty::Uuids(uuids) => {
+                let mut services = uuids
+                    .into_iter()
+                    .map(|uuid| uuid.to_string())
+                    .collect::<Vec<_>>();
+                services.sort_unstable();
+                self.services = services;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Filter applied when looking for devices in the inventory, read from the query string
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct InventoryQuery {
+    /// Only devices not handled by any driver
+    pub unsupported: bool,
+    /// Only devices advertising data for this company identifier, like `0x004c`
+    #[serde(deserialize_with = "deserialize_company")]
+    pub company: Option<u16>,
+    /// Only devices whose name contains this value, case insensitive
+    pub name: Option<String>,
+    /// Only devices seen within that many seconds
+    #[serde(deserialize_with = "deserialize_seconds")]
+    pub seen_within: Option<Duration>,
+}
+
+fn deserialize_company<'de, D>(deserializer: D) -> Result<Option<u16>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|value| parse_company_id(&value).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+fn deserialize_seconds<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Ok(Option::<u64>::deserialize(deserializer)?.map(Duration::from_secs))
+}
+
+impl InventoryQuery {
+    fn matches(&self, entry: &InventoryEntry, now: u64) -> bool {
+        if self.unsupported && entry.driver.is_some() {
+            return false;
+        }
+        if let Some(company) = self.company
+            && !entry.manufacturers.iter().any(|item| item.id == company)
+        {
+            return false;
+        }
+        if let Some(ref name) = self.name
+            && !entry
+                .name
+                .as_deref()
+                .is_some_and(|value| value.to_lowercase().contains(&name.to_lowercase()))
+        {
+            return false;
+        }
+        if let Some(within) = self.seen_within
+            && entry.last_seen + within.as_secs() < now
+        {
+            return false;
+        }
+        true
+    }
+}
+
+/// Keeps track of every device seen by the adapter.
+#[derive(Debug)]
+pub(crate) struct Inventory {
+    path: Option<PathBuf>,
+    devices: Mutex<HashMap<bluer::Address, InventoryEntry>>,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|value| value.as_secs())
+        .unwrap_or_default()
+}
+
+impl Inventory {
+    pub(crate) async fn observe(
+        &self,
+        device: &bluer::Device,
+        identity: Option<&str>,
+        driver: Option<&'static str>,
+    ) {
+        let properties = match device.all_properties().await {
+            Ok(value) => value,
+            Err(err) => {
+                tracing::debug!(
+                    message = "unable to read device properties",
+                    error.message = err.to_string(),
+                );
+                Vec::new()
+            }
+        };
+        let now = unix_now();
+        let address = device.address();
+        let mut devices = self.devices.lock().expect("inventory lock poisoned");
+        let entry = devices.entry(address).or_insert_with(|| {
+            tracing::info!(message = "new device discovered", address = %address);
+            InventoryEntry::new(address, now)
+        });
+        properties
+            .into_iter()
+            .for_each(|property| entry.apply(property));
+        if let Some(identity) = identity {
+            entry.identity = Some(identity.to_string());
+        }
+        if driver.is_some() {
+            entry.driver = driver;
+        }
+        entry.last_seen = now;
+        entry.advertisements += 1;
+    }
+
+    pub(crate) fn query(&self, query: &InventoryQuery) -> Vec<InventoryEntry> {
+        let now = unix_now();
+        let devices = self.devices.lock().expect("inventory lock poisoned");
+        let mut result = devices
+            .values()
+            .filter(|entry| query.matches(entry, now))
+            .cloned()
+            .collect::<Vec<_>>();
+        result.sort_by(|first, second| first.address.cmp(&second.address));
+        result
+    }
+
+    /// Serves the devices on `/inventory`, filtered by the query string like
+    /// `/inventory?unsupported=true&company=0x004c&name=flower&seen_within=600`
+    pub(crate) fn router(self: &Arc<Self>) -> axum::Router {
+        axum::Router::new()
+            .route("/inventory", axum::routing::get(handle_inventory))
+            .with_state(self.clone())
+    }
+
+    /// Removes the old devices and writes the inventory in the configured file
+    pub(crate) async fn persist(self: &Arc<Self>) -> anyhow::Result<()> {
+        let this = self.clone();
+        tokio::task::spawn_blocking(move || this.write()).await?
+    }
+
+    fn write(&self) -> anyhow::Result<()> {
+        let now = unix_now();
+        self.devices
+            .lock()
+            .expect("inventory lock poisoned")
+            .retain(|_, entry| entry.last_seen + RETENTION.as_secs() >= now);
+        let Some(ref path) = self.path else {
+            return Ok(());
+        };
+        let entries = self.query(&InventoryQuery::default());
+        write_atomically(path, &serde_json::to_vec_pretty(&entries)?)
+            .with_context(|| format!("unable to write inventory to {path:?}"))
+    }
+}
+
+async fn handle_inventory(
+    axum::extract::State(inventory): axum::extract::State<Arc<Inventory>>,
+    axum::extract::Query(query): axum::extract::Query<InventoryQuery>,
+) -> impl axum::response::IntoResponse {
+    let entries = inventory.query(&query);
+    match serde_json::to_string_pretty(&entries) {
+        Ok(body) => (
+            axum::http::StatusCode::OK,
+            [(axum::http::header::CONTENT_TYPE, "application/json")],
+            body,
+        ),
+        Err(err) => (
+            axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+            [(axum::http::header::CONTENT_TYPE, "text/plain")],
+            err.to_string(),
+        ),
+    }
+}
+
+fn write_atomically(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, content)?;
+    std::fs::rename(tmp, path)
+}
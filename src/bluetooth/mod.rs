@@ -1,27 +1,96 @@
-use std::time::Duration;
+use std::{
+    collections::HashSet,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
 
 use anyhow::Context;
-use bluer::AdapterEvent;
+use bluer::{AdapterEvent, AdapterProperty};
 use opentelemetry::KeyValue;
 use tokio_stream::StreamExt;
 use tokio_util::sync::CancellationToken;
 
+mod beacon;
+mod company;
+mod devices;
+mod identity;
+mod inventory;
+mod presence;
+mod rssi;
+pub(crate) mod tools;
 mod xiaomi_lywsd03mmc_atc;
 mod xiaomi_miflora;
 
+pub(crate) use devices::{DeviceSettings, SharedDeviceSettings};
+
+/// Names of the components reported by the health endpoints
+const HEALTH_ADAPTER: &str = "bluetooth.adapter.powered";
+const HEALTH_DISCOVERY: &str = "bluetooth.discovery.active";
+const HEALTH_EVENTS: &str = "bluetooth.events";
+
 #[derive(Debug)]
-pub(crate) struct BluetoothConfig {}
+pub(crate) struct BluetoothConfig {
+    /// Drivers devices are sent to, all of them when empty
+    drivers: HashSet<&'static str>,
+    identities: Vec<identity::IdentityKey>,
+    devices: SharedDeviceSettings,
+    inventory: inventory::InventoryConfig,
+    presence: presence::PresenceConfig,
+    rssi: rssi::RssiConfig,
+}
 
 impl crate::Configurable for BluetoothConfig {
-    fn from_env() -> anyhow::Result<Self> {
-        Ok(Self {})
+    fn from_source(source: &crate::config::Source) -> Self {
+        Self {
+            drivers: source
+                .parse_list::<Driver>("BLUETOOTH_DRIVERS", "bluetooth.drivers")
+                .unwrap_or_default()
+                .into_iter()
+                .map(|driver| driver.0)
+                .collect(),
+            identities: source
+                .parse_list("BLUETOOTH_IDENTITY_KEYS", "bluetooth.identity_keys")
+                .unwrap_or_default(),
+            devices: SharedDeviceSettings::new(DeviceSettings::from_source(source)),
+            inventory: inventory::InventoryConfig::from_source(source),
+            presence: presence::PresenceConfig::from_source(source),
+            rssi: rssi::RssiConfig::from_source(source),
+        }
+    }
+}
+
+/// Name of a driver, checked against the known ones
+struct Driver(&'static str);
+
+impl FromStr for Driver {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        tools::DRIVERS
+            .into_iter()
+            .find(|driver| *driver == value.trim())
+            .map(Self)
+            .with_context(|| {
+                format!(
+                    "unknown driver {value:?}, expected one of {}",
+                    tools::DRIVERS.join(", ")
+                )
+            })
     }
 }
 
 impl BluetoothConfig {
+    /// Settings that can be reloaded while running
+    pub(crate) fn devices(&self) -> SharedDeviceSettings {
+        self.devices.clone()
+    }
+
     pub(crate) async fn build(
         &self,
         cancel_token: CancellationToken,
+        health: &crate::health::Health,
+        supervisor: &crate::Supervisor,
     ) -> anyhow::Result<BluetoothCollector> {
         let session = bluer::Session::new()
             .await
@@ -36,21 +105,24 @@ impl BluetoothConfig {
         Ok(BluetoothCollector {
             adapter,
             cancel_token,
-            events_counter: meter
-                .u64_counter("bluetooth.events")
-                .with_description("Number of events received")
-                .build(),
-            device_counter: meter
-                .u64_gauge("bluetooth.devices")
-                .with_description("Number of discovered devices")
-                .build(),
-            device_rssi: meter
-                .i64_gauge("bluetooth.device.rssi")
-                .with_description("Received Signal Strength Indicator")
-                .build(),
+            health: health.clone(),
+            events_counter: crate::metrics::EVENTS.u64_counter(&meter),
+            device_counter: crate::metrics::DEVICES.u64_gauge(&meter),
+            device_rssi: crate::metrics::DEVICE_RSSI.i64_gauge(&meter),
+            drivers: self.drivers.clone(),
+            identities: identity::IdentityResolver::new(self.identities.clone()),
+            devices: self.devices.clone(),
+            inventory: self.inventory.build(),
+            presence: self.presence.build(),
+            rssi: self.rssi.build(),
             //
+            last_reading: Default::default(),
+            beacon: beacon::BeaconCollector::new(self.rssi.path_loss()),
             xiaomi_lywsd03mmc_atc: Default::default(),
-            xiaomi_miflora: Default::default(),
+            xiaomi_miflora: xiaomi_miflora::XiaomiMifloraCollector::new(
+                self.devices.clone(),
+                supervisor,
+            ),
         })
     }
 }
@@ -59,42 +131,63 @@ impl BluetoothConfig {
 pub(crate) struct BluetoothCollector {
     adapter: bluer::Adapter,
     cancel_token: CancellationToken,
+    health: crate::health::Health,
     events_counter: opentelemetry::metrics::Counter<u64>,
-    device_counter: opentelemetry::metrics::Gauge<u64>,
-    device_rssi: opentelemetry::metrics::Gauge<i64>,
+    device_counter: crate::metrics::Gauge<u64>,
+    device_rssi: crate::metrics::Gauge<i64>,
+    drivers: HashSet<&'static str>,
+    identities: identity::IdentityResolver,
+    devices: SharedDeviceSettings,
+    inventory: std::sync::Arc<inventory::Inventory>,
+    presence: presence::PresenceTracker,
+    rssi: rssi::RssiTracker,
+    /// When and by which driver the last device was collected
+    last_reading: Mutex<Option<(Instant, &'static str)>>,
     //
+    beacon: beacon::BeaconCollector,
     xiaomi_lywsd03mmc_atc: xiaomi_lywsd03mmc_atc::XiaomiLywsd03mmcAtcCollector,
     xiaomi_miflora: xiaomi_miflora::XiaomiMifloraCollector,
 }
 
 impl BluetoothCollector {
     fn track_event(&self, event: &AdapterEvent) {
+        // the presence of devices is reported by the presence tracker, the addresses aren't
+        // labels here
+        let kind = match event {
+            AdapterEvent::DeviceAdded(_) => "device-added",
+            AdapterEvent::DeviceRemoved(_) => "device-removed",
+            AdapterEvent::PropertyChanged(_) => "property-changed",
+        };
+        self.events_counter.add(1, &[KeyValue::new("kind", kind)]);
+        self.health.touch(HEALTH_EVENTS);
         match event {
-            AdapterEvent::DeviceAdded(address) => {
-                self.events_counter.add(
-                    1,
-                    &[
-                        KeyValue::new("kind", "device-added"),
-                        KeyValue::new("address", address.to_string()),
-                    ],
-                );
-            }
-            AdapterEvent::DeviceRemoved(address) => {
-                self.events_counter.add(
-                    1,
-                    &[
-                        KeyValue::new("kind", "device-removed"),
-                        KeyValue::new("address", address.to_string()),
-                    ],
-                );
+            AdapterEvent::PropertyChanged(AdapterProperty::Powered(powered)) => {
+                self.health.set_ready(HEALTH_ADAPTER, *powered);
             }
-            AdapterEvent::PropertyChanged(_) => {
-                self.events_counter
-                    .add(1, &[KeyValue::new("kind", "property-changed")]);
+            AdapterEvent::PropertyChanged(AdapterProperty::Discovering(discovering)) => {
+                self.health.set_ready(HEALTH_DISCOVERY, *discovering);
             }
+            _ => {}
         }
     }
 
+    async fn track_presence(&self, device: &bluer::Device, identity: Option<&str>) {
+        if self.presence.is_empty() {
+            return;
+        }
+        let ibeacon = device
+            .manufacturer_data()
+            .await
+            .ok()
+            .flatten()
+            .and_then(|data| {
+                data.get(&beacon::APPLE_COMPANY_ID)
+                    .and_then(|value| beacon::IBeacon::parse(value))
+            });
+        self.presence
+            .observe(device.address(), identity, ibeacon.as_ref());
+    }
+
     #[tracing::instrument(
         parent = None,
         skip_all,
@@ -102,9 +195,12 @@ impl BluetoothCollector {
             network.peer.address = self.adapter.name(),
             network.protocol.name = "bluetooth",
             ble.address = tracing::field::Empty,
+            ble.alias = tracing::field::Empty,
+            ble.identity = tracing::field::Empty,
             ble.icon = tracing::field::Empty,
             ble.name = tracing::field::Empty,
             ble.rssi = tracing::field::Empty,
+            ble.supported = tracing::field::Empty,
             resource.name = "bluetooth/handle_event",
             otel.status_code = tracing::field::Empty,
             span.kind = "server",
@@ -115,43 +211,100 @@ impl BluetoothCollector {
         self.track_event(&event);
         let span = tracing::Span::current();
         let AdapterEvent::DeviceAdded(address) = event else {
+            // no device is collected, the span is dropped by the sampling like unsupported ones
+            span.record("ble.supported", false);
             span.record("otel.status_code", "OK");
             return Ok(());
         };
         span.record("ble.address", address.to_string());
 
         let device = self.adapter.device(address)?;
+        // resolving private addresses to a stable identity
+        let identity = self.identities.resolve(address);
+        let settings = self.devices.current();
+        let allowed = settings.is_allowed(address, identity);
         // collecting attributes
-        let mut attributes = Vec::with_capacity(2);
-        attributes.push(KeyValue::new("address", address.to_string()));
+        let mut attributes = Vec::with_capacity(3);
+        if let Some(identity) = identity {
+            span.record("ble.identity", identity);
+            attributes.push(KeyValue::new("identity", identity.to_string()));
+        } else {
+            attributes.push(KeyValue::new("address", address.to_string()));
+        }
+        if let Some(alias) = settings.alias(address, identity) {
+            span.record("ble.alias", alias);
+            attributes.push(KeyValue::new("alias", alias.to_string()));
+        }
         if let Ok(Some(name)) = device.name().await {
             span.record("ble.name", name.as_str());
             attributes.push(KeyValue::new("name", name));
         }
-        if let Ok(Some(rssi)) = device.rssi().await {
+        if allowed && let Ok(Some(rssi)) = device.rssi().await {
             span.record("ble.rssi", rssi);
             self.device_rssi.record(rssi as i64, &attributes);
+            let key = identity
+                .map(String::from)
+                .unwrap_or_else(|| address.to_string());
+            self.rssi.observe(key, rssi, &attributes);
         }
         if let Ok(Some(icon)) = device.icon().await {
             span.record("ble.icon", icon);
         }
-        // dispatching devices
-        if self
-            .xiaomi_lywsd03mmc_atc
-            .collect(&device, &attributes)
-            .await?
-        {
-            span.record("otel.status_code", "OK");
-            return Ok(());
+        self.track_presence(&device, identity).await;
+        // dispatching devices, unless excluded by the allow and deny lists
+        let driver = if allowed {
+            self.dispatch(&device, &attributes).await
+        } else {
+            Ok(None)
         };
-        if self.xiaomi_miflora.collect(&device, &attributes).await? {
+        self.inventory
+            .observe(&device, identity, driver_name(&driver))
+            .await;
+        if let Ok(Some(name)) = driver {
+            *self
+                .last_reading
+                .lock()
+                .expect("last reading lock poisoned") = Some((Instant::now(), name));
+        }
+        // used to only export the spans of supported devices
+        span.record("ble.supported", matches!(driver, Ok(Some(_))));
+        if driver?.is_some() {
             span.record("otel.status_code", "OK");
             return Ok(());
-        };
+        }
         tracing::trace!(message = "unsupported device");
         Ok(())
     }
 
+    fn enabled(&self, driver: &str) -> bool {
+        self.drivers.is_empty() || self.drivers.contains(driver)
+    }
+
+    /// Sends the device to the first driver supporting it and returns its name
+    async fn dispatch(
+        &self,
+        device: &bluer::Device,
+        attributes: &[KeyValue],
+    ) -> anyhow::Result<Option<&'static str>> {
+        if self.enabled(xiaomi_lywsd03mmc_atc::DRIVER)
+            && self
+                .xiaomi_lywsd03mmc_atc
+                .collect(device, attributes)
+                .await?
+        {
+            return Ok(Some(xiaomi_lywsd03mmc_atc::DRIVER));
+        };
+        if self.enabled(xiaomi_miflora::DRIVER)
+            && self.xiaomi_miflora.collect(device, attributes).await?
+        {
+            return Ok(Some(xiaomi_miflora::DRIVER));
+        };
+        if self.enabled(beacon::DRIVER) && self.beacon.collect(device, attributes).await? {
+            return Ok(Some(beacon::DRIVER));
+        };
+        Ok(None)
+    }
+
     #[tracing::instrument(
         parent = None,
         skip(self),
@@ -163,11 +316,40 @@ impl BluetoothCollector {
     async fn handle_heartbeat(&self) -> anyhow::Result<()> {
         let addresses = self.adapter.device_addresses().await?;
         self.device_counter.record(addresses.len() as u64, &[]);
-        Ok(())
+        crate::systemd::status(&self.status(addresses.len()));
+        self.presence.refresh();
+        self.rssi.prune();
+        self.inventory.persist().await
+    }
+
+    /// Routes served next to the health endpoints
+    pub(crate) fn router(&self) -> axum::Router {
+        self.inventory.router()
+    }
+
+    /// Summary displayed by `systemctl status`
+    fn status(&self, devices: usize) -> String {
+        let last_reading = *self
+            .last_reading
+            .lock()
+            .expect("last reading lock poisoned");
+        match last_reading {
+            Some((at, driver)) => format!(
+                "{devices} devices seen, last reading {}s ago by {driver}",
+                at.elapsed().as_secs()
+            ),
+            None => format!("{devices} devices seen, no reading yet"),
+        }
     }
 
     #[tracing::instrument(skip(self), err(Debug))]
     pub(crate) async fn run(self) -> anyhow::Result<()> {
+        let result = self.scan().await;
+        self.shutdown().await;
+        result
+    }
+
+    async fn scan(&self) -> anyhow::Result<()> {
         tracing::info!("starting reader");
         self.adapter
             .set_powered(true)
@@ -177,9 +359,18 @@ impl BluetoothCollector {
             .set_discovery_filter(bluer::DiscoveryFilter::default())
             .await
             .context("unable to set discovery filter")?;
+        self.health
+            .set_ready(HEALTH_ADAPTER, self.adapter.is_powered().await?);
         tracing::info!("preparing reader");
         let mut events = self.adapter.discover_devices_with_changes().await?;
+        self.health.set_ready(HEALTH_DISCOVERY, true);
+        self.health.touch(HEALTH_EVENTS);
         let mut heartbeat = tokio::time::interval(Duration::from_secs(30));
+        // pinged from the loop so that systemd notices when it hangs
+        let watchdog_interval = crate::systemd::watchdog_interval();
+        let mut watchdog =
+            tokio::time::interval(watchdog_interval.unwrap_or(Duration::from_secs(60)));
+        crate::systemd::ready();
         while !self.cancel_token.is_cancelled() {
             tokio::select! {
                 maybe_event = events.next() => {
@@ -196,8 +387,31 @@ impl BluetoothCollector {
                 _ = heartbeat.tick() => {
                     let _ = self.handle_heartbeat().await;
                 }
+                _ = watchdog.tick(), if watchdog_interval.is_some() => {
+                    crate::systemd::watchdog();
+                }
             }
         }
+        // the discovery session ends with the stream
+        drop(events);
+        self.health.set_ready(HEALTH_DISCOVERY, false);
+        tracing::info!("discovery stopped");
         Ok(())
     }
+
+    /// Stops the drivers once no device can be dispatched to them anymore
+    async fn shutdown(self) {
+        self.xiaomi_miflora.shutdown().await;
+        if let Err(err) = self.inventory.persist().await {
+            tracing::warn!(
+                message = "unable to persist inventory",
+                error.message = format!("{err:#}"),
+            );
+        }
+        tracing::info!("reader stopped");
+    }
+}
+
+fn driver_name(driver: &anyhow::Result<Option<&'static str>>) -> Option<&'static str> {
+    driver.as_ref().ok().copied().flatten()
 }
@@ -0,0 +1,223 @@
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use opentelemetry::KeyValue;
+use uuid::Uuid;
+
+use super::beacon::IBeacon;
+
+const DEFAULT_AWAY_TIMEOUT: Duration = Duration::from_secs(60 * 5); // 5min
+
+/// How a logical device is recognized in the advertisements
+#[derive(Clone, Debug)]
+pub(crate) enum PresenceMatcher {
+    /// Devices with a stable address, like Tile or NutTag tags
+    Address(bluer::Address),
+    /// Devices rotating their address, like phones or watches, resolved with their IRK
+    Identity(String),
+    /// iBeacon frames, optionally restricted to a major and minor
+    IBeacon {
+        uuid: Uuid,
+        major: Option<u16>,
+        minor: Option<u16>,
+    },
+}
+
+impl FromStr for PresenceMatcher {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (kind, value) = value
+            .split_once(':')
+            .context("expected a matcher like <kind>:<value>")?;
+        match kind {
+            "address" => bluer::Address::from_str(value)
+                .map(Self::Address)
+                .with_context(|| format!("invalid address {value:?}")),
+            "identity" => Ok(Self::Identity(value.to_string())),
+            "ibeacon" => {
+                let mut parts = value.split(':');
+                let uuid = parts.next().unwrap_or_default();
+                let uuid = Uuid::parse_str(uuid)
+                    .with_context(|| format!("invalid ibeacon uuid {uuid:?}"))?;
+                let major = parts
+                    .next()
+                    .map(u16::from_str)
+                    .transpose()
+                    .context("invalid ibeacon major")?;
+                let minor = parts
+                    .next()
+                    .map(u16::from_str)
+                    .transpose()
+                    .context("invalid ibeacon minor")?;
+                Ok(Self::IBeacon { uuid, major, minor })
+            }
+            other => Err(anyhow::anyhow!("unknown matcher kind {other:?}")),
+        }
+    }
+}
+
+impl PresenceMatcher {
+    fn matches(
+        &self,
+        address: bluer::Address,
+        identity: Option<&str>,
+        ibeacon: Option<&IBeacon>,
+    ) -> bool {
+        match self {
+            Self::Address(expected) => *expected == address,
+            Self::Identity(expected) => identity.is_some_and(|value| value == expected),
+            Self::IBeacon { uuid, major, minor } => ibeacon.is_some_and(|beacon| {
+                beacon.uuid == *uuid
+                    && major.is_none_or(|value| value == beacon.major)
+                    && minor.is_none_or(|value| value == beacon.minor)
+            }),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct PresenceDevice {
+    name: String,
+    matcher: PresenceMatcher,
+}
+
+impl FromStr for PresenceDevice {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (name, matcher) = value
+            .split_once('=')
+            .context("expected a device like <name>=<kind>:<value>")?;
+        Ok(Self {
+            name: name.trim().to_string(),
+            matcher: matcher
+                .trim()
+                .parse()
+                .with_context(|| format!("invalid matcher for device {name:?}"))?,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct PresenceConfig {
+    devices: Vec<PresenceDevice>,
+    away_timeout: Duration,
+}
+
+impl crate::Configurable for PresenceConfig {
+    fn from_source(source: &crate::config::Source) -> Self {
+        Self {
+            devices: source
+                .parse_list("BLUETOOTH_PRESENCE_DEVICES", "bluetooth.presence.devices")
+                .unwrap_or_default(),
+            away_timeout: source
+                .parse::<u64>(
+                    "BLUETOOTH_PRESENCE_AWAY_TIMEOUT",
+                    "bluetooth.presence.away_timeout",
+                )
+                .map_or(DEFAULT_AWAY_TIMEOUT, Duration::from_secs),
+        }
+    }
+}
+
+impl PresenceConfig {
+    pub(crate) fn build(&self) -> PresenceTracker {
+        let meter = opentelemetry::global::meter("presence");
+
+        PresenceTracker {
+            devices: self.devices.clone(),
+            away_timeout: self.away_timeout,
+            sightings: Default::default(),
+            state: crate::metrics::PRESENCE_STATE.u64_gauge(&meter),
+            last_seen: crate::metrics::PRESENCE_LAST_SEEN.u64_gauge(&meter),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Sighting {
+    last_seen: SystemTime,
+    present: bool,
+}
+
+/// Keeps track of the configured devices and decides if they are present or away.
+///
+/// A device becomes present as soon as it's seen and only goes away once it hasn't
+/// been seen for the configured timeout, so a missed advertisement doesn't flap the state.
+#[derive(Debug)]
+pub(crate) struct PresenceTracker {
+    devices: Vec<PresenceDevice>,
+    away_timeout: Duration,
+    sightings: Mutex<HashMap<String, Sighting>>,
+    state: crate::metrics::Gauge<u64>,
+    last_seen: crate::metrics::Gauge<u64>,
+}
+
+impl PresenceTracker {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+
+    pub(crate) fn observe(
+        &self,
+        address: bluer::Address,
+        identity: Option<&str>,
+        ibeacon: Option<&IBeacon>,
+    ) {
+        let now = SystemTime::now();
+        let mut sightings = self.sightings.lock().expect("presence lock poisoned");
+        for device in self
+            .devices
+            .iter()
+            .filter(|device| device.matcher.matches(address, identity, ibeacon))
+        {
+            let sighting = sightings
+                .entry(device.name.clone())
+                .or_insert_with(|| Sighting {
+                    last_seen: now,
+                    present: false,
+                });
+            sighting.last_seen = now;
+            if !sighting.present {
+                tracing::info!(message = "device arrived", device = device.name);
+                sighting.present = true;
+                self.record(&device.name, sighting);
+            }
+        }
+    }
+
+    /// Moves the devices that haven't been seen for too long to away and records the state
+    pub(crate) fn refresh(&self) {
+        let now = SystemTime::now();
+        let mut sightings = self.sightings.lock().expect("presence lock poisoned");
+        for device in self.devices.iter() {
+            let Some(sighting) = sightings.get_mut(&device.name) else {
+                self.state
+                    .record(0, &[KeyValue::new("device", device.name.clone())]);
+                continue;
+            };
+            let elapsed = now
+                .duration_since(sighting.last_seen)
+                .unwrap_or(Duration::ZERO);
+            if sighting.present && elapsed > self.away_timeout {
+                tracing::info!(message = "device left", device = device.name, elapsed = ?elapsed);
+                sighting.present = false;
+            }
+            self.record(&device.name, sighting);
+        }
+    }
+
+    fn record(&self, name: &str, sighting: &Sighting) {
+        let attributes = [KeyValue::new("device", name.to_string())];
+        self.state.record(sighting.present as u64, &attributes);
+        if let Ok(timestamp) = sighting.last_seen.duration_since(SystemTime::UNIX_EPOCH) {
+            self.last_seen.record(timestamp.as_secs(), &attributes);
+        }
+    }
+}
@@ -0,0 +1,268 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use opentelemetry::KeyValue;
+
+use crate::{config::Source, metrics};
+
+const DEFAULT_WINDOW: usize = 10;
+const DEFAULT_EMA_ALPHA: f64 = 0.3;
+const DEFAULT_KALMAN_PROCESS_NOISE: f64 = 0.5;
+const DEFAULT_KALMAN_MEASUREMENT_NOISE: f64 = 4.0;
+const DEFAULT_REFERENCE_POWER: f64 = -59.0;
+const DEFAULT_PATH_LOSS_EXPONENT: f64 = 2.0;
+/// Devices that haven't been seen for that long are forgotten
+const EXPIRATION: Duration = Duration::from_secs(60 * 10); // 10min
+
+/// Log-distance path loss model used to turn a signal strength into a distance
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct PathLossModel {
+    /// Signal strength received at 1 meter, in dBm
+    pub reference_power: f64,
+    /// 2.0 in free space, usually between 2.7 and 4.0 indoor
+    pub exponent: f64,
+}
+
+impl Default for PathLossModel {
+    fn default() -> Self {
+        Self {
+            reference_power: DEFAULT_REFERENCE_POWER,
+            exponent: DEFAULT_PATH_LOSS_EXPONENT,
+        }
+    }
+}
+
+impl crate::Configurable for PathLossModel {
+    fn from_source(source: &Source) -> Self {
+        let default = Self::default();
+        Self {
+            reference_power: source
+                .parse(
+                    "BLUETOOTH_PATH_LOSS_REFERENCE_POWER",
+                    "bluetooth.path_loss.reference_power",
+                )
+                .unwrap_or(default.reference_power),
+            exponent: parse_checked(
+                source,
+                "BLUETOOTH_PATH_LOSS_EXPONENT",
+                "bluetooth.path_loss.exponent",
+                default.exponent,
+                |exponent| exponent > 0.0,
+                "strictly positive",
+            ),
+        }
+    }
+}
+
+impl PathLossModel {
+    /// Estimates the distance in meters using the configured reference power
+    pub(crate) fn distance(&self, rssi: f64) -> f64 {
+        self.distance_with(self.reference_power, rssi)
+    }
+
+    /// Estimates the distance in meters using the power at 1 meter advertised by the device
+    pub(crate) fn distance_with(&self, reference_power: f64, rssi: f64) -> f64 {
+        10f64.powf((reference_power - rssi) / (10.0 * self.exponent))
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Smoothing {
+    /// Exponential moving average
+    Ema { alpha: f64 },
+    /// One dimensional Kalman filter
+    Kalman {
+        process_noise: f64,
+        measurement_noise: f64,
+    },
+}
+
+#[derive(Debug)]
+pub(crate) struct RssiConfig {
+    window: usize,
+    smoothing: Smoothing,
+    path_loss: PathLossModel,
+}
+
+impl crate::Configurable for RssiConfig {
+    fn from_source(source: &Source) -> Self {
+        let (env, key) = ("BLUETOOTH_RSSI_SMOOTHING", "bluetooth.rssi.smoothing");
+        let ema = match source.string(env, key).as_deref() {
+            None | Some("kalman") => false,
+            Some("ema") => true,
+            Some(other) => {
+                source.report(
+                    env,
+                    key,
+                    format_args!("unknown smoothing {other:?}, expected kalman or ema"),
+                );
+                false
+            }
+        };
+        // both are read so that the settings of the other smoothing aren't reported as unknown
+        let alpha = parse_checked(
+            source,
+            "BLUETOOTH_RSSI_EMA_ALPHA",
+            "bluetooth.rssi.ema_alpha",
+            DEFAULT_EMA_ALPHA,
+            |alpha| alpha > 0.0 && alpha <= 1.0,
+            "in ]0, 1]",
+        );
+        let kalman = Smoothing::Kalman {
+            process_noise: parse_checked(
+                source,
+                "BLUETOOTH_RSSI_KALMAN_PROCESS_NOISE",
+                "bluetooth.rssi.kalman_process_noise",
+                DEFAULT_KALMAN_PROCESS_NOISE,
+                |noise| noise >= 0.0,
+                "positive",
+            ),
+            measurement_noise: parse_checked(
+                source,
+                "BLUETOOTH_RSSI_KALMAN_MEASUREMENT_NOISE",
+                "bluetooth.rssi.kalman_measurement_noise",
+                DEFAULT_KALMAN_MEASUREMENT_NOISE,
+                |noise| noise > 0.0,
+                "strictly positive",
+            ),
+        };
+        let smoothing = if ema {
+            Smoothing::Ema { alpha }
+        } else {
+            kalman
+        };
+        Self {
+            window: source
+                .parse("BLUETOOTH_RSSI_WINDOW", "bluetooth.rssi.window")
+                .unwrap_or(DEFAULT_WINDOW)
+                .max(1),
+            smoothing,
+            path_loss: PathLossModel::from_source(source),
+        }
+    }
+}
+
+/// Reads a number, reporting the values the filters or the distances would diverge with
+fn parse_checked(
+    source: &Source,
+    env: &str,
+    key: &str,
+    default: f64,
+    valid: fn(f64) -> bool,
+    expected: &str,
+) -> f64 {
+    match source.parse::<f64>(env, key) {
+        Some(value) if !valid(value) => {
+            source.report(env, key, format_args!("{value} should be {expected}"));
+            default
+        }
+        value => value.unwrap_or(default),
+    }
+}
+
+impl RssiConfig {
+    pub(crate) fn path_loss(&self) -> PathLossModel {
+        self.path_loss
+    }
+
+    pub(crate) fn build(&self) -> RssiTracker {
+        let meter = opentelemetry::global::meter("bluetooth");
+
+        RssiTracker {
+            window: self.window,
+            smoothing: self.smoothing,
+            path_loss: self.path_loss,
+            devices: Default::default(),
+            smoothed: metrics::DEVICE_RSSI_SMOOTHED.f64_gauge(&meter),
+            variance: metrics::DEVICE_RSSI_VARIANCE.f64_gauge(&meter),
+            distance: metrics::DEVICE_DISTANCE.f64_gauge(&meter),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct DeviceRssi {
+    samples: VecDeque<f64>,
+    estimate: f64,
+    covariance: f64,
+    last_seen: Instant,
+}
+
+impl DeviceRssi {
+    fn new(value: f64) -> Self {
+        Self {
+            samples: VecDeque::new(),
+            estimate: value,
+            covariance: 1.0,
+            last_seen: Instant::now(),
+        }
+    }
+
+    fn push(&mut self, value: f64, window: usize, smoothing: Smoothing) {
+        if self.samples.len() >= window {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(value);
+        self.last_seen = Instant::now();
+
+        match smoothing {
+            Smoothing::Ema { alpha } => {
+                self.estimate = alpha * value + (1.0 - alpha) * self.estimate;
+            }
+            Smoothing::Kalman {
+                process_noise,
+                measurement_noise,
+            } => {
+                let covariance = self.covariance + process_noise;
+                let gain = covariance / (covariance + measurement_noise);
+                self.estimate += gain * (value - self.estimate);
+                self.covariance = (1.0 - gain) * covariance;
+            }
+        }
+    }
+
+    fn variance(&self) -> f64 {
+        let count = self.samples.len() as f64;
+        let mean = self.samples.iter().sum::<f64>() / count;
+        self.samples
+            .iter()
+            .map(|value| (value - mean).powi(2))
+            .sum::<f64>()
+            / count
+    }
+}
+
+/// Keeps a rolling window of the signal strength of every device to smooth it.
+#[derive(Debug)]
+pub(crate) struct RssiTracker {
+    window: usize,
+    smoothing: Smoothing,
+    path_loss: PathLossModel,
+    devices: Mutex<HashMap<String, DeviceRssi>>,
+    smoothed: metrics::Gauge<f64>,
+    variance: metrics::Gauge<f64>,
+    distance: metrics::Gauge<f64>,
+}
+
+impl RssiTracker {
+    pub(crate) fn observe(&self, key: String, rssi: i16, attributes: &[KeyValue]) {
+        let value = rssi as f64;
+        let mut devices = self.devices.lock().expect("rssi lock poisoned");
+        let device = devices.entry(key).or_insert_with(|| DeviceRssi::new(value));
+        device.push(value, self.window, self.smoothing);
+
+        self.smoothed.record(device.estimate, attributes);
+        self.variance.record(device.variance(), attributes);
+        self.distance
+            .record(self.path_loss.distance(device.estimate), attributes);
+    }
+
+    /// Forgets about the devices that haven't been seen recently
+    pub(crate) fn prune(&self) {
+        let mut devices = self.devices.lock().expect("rssi lock poisoned");
+        devices.retain(|_, device| device.last_seen.elapsed() < EXPIRATION);
+    }
+}
@@ -0,0 +1,163 @@
+//! Helpers behind the `scan` and `decode` commands, nothing is recorded.
+
+use std::{collections::BTreeSet, time::Duration};
+
+use anyhow::Context;
+use bluer::AdapterEvent;
+use tokio_stream::StreamExt;
+
+use super::{beacon, xiaomi_lywsd03mmc_atc, xiaomi_miflora};
+
+/// Drivers accepted by [`decode`]
+pub const DRIVERS: [&str; 3] = [
+    xiaomi_lywsd03mmc_atc::DRIVER,
+    xiaomi_miflora::DRIVER,
+    beacon::DRIVER,
+];
+
+#[derive(Debug)]
+pub struct ScannedDevice {
+    pub address: String,
+    pub name: Option<String>,
+    pub rssi: Option<i16>,
+    pub driver: Option<&'static str>,
+}
+
+/// Lists the devices discovered during the given duration
+pub async fn scan(duration: Duration) -> anyhow::Result<Vec<ScannedDevice>> {
+    let session = bluer::Session::new()
+        .await
+        .context("unable to create session")?;
+    let adapter = session
+        .default_adapter()
+        .await
+        .context("unable to find default adapter")?;
+    adapter
+        .set_powered(true)
+        .await
+        .context("unable to turn on adapter")?;
+    let mut events = adapter.discover_devices().await?;
+    let mut addresses = BTreeSet::new();
+    let deadline = tokio::time::sleep(duration);
+    tokio::pin!(deadline);
+    loop {
+        tokio::select! {
+            event = events.next() => match event {
+                Some(AdapterEvent::DeviceAdded(address)) => {
+                    addresses.insert(address);
+                }
+                Some(AdapterEvent::DeviceRemoved(address)) => {
+                    addresses.remove(&address);
+                }
+                Some(AdapterEvent::PropertyChanged(_)) => {}
+                None => break,
+            },
+            _ = &mut deadline => break,
+        }
+    }
+    let mut devices = Vec::with_capacity(addresses.len());
+    for address in addresses {
+        // the device can vanish between the discovery and the reading of its properties
+        let device = match adapter.device(address) {
+            Ok(device) => device,
+            Err(err) => {
+                tracing::warn!(
+                    message = "unable to read device",
+                    ble.address = %address,
+                    error.message = format!("{err:#}"),
+                );
+                continue;
+            }
+        };
+        let driver = detect(&device).await.unwrap_or_else(|err| {
+            tracing::warn!(
+                message = "unable to detect driver",
+                ble.address = %address,
+                error.message = format!("{err:#}"),
+            );
+            None
+        });
+        devices.push(ScannedDevice {
+            address: address.to_string(),
+            name: device.name().await.ok().flatten(),
+            rssi: device.rssi().await.ok().flatten(),
+            driver,
+        });
+    }
+    Ok(devices)
+}
+
+/// Name of the driver that would collect the device
+async fn detect(device: &bluer::Device) -> anyhow::Result<Option<&'static str>> {
+    let service_data = device.service_data().await?.unwrap_or_default();
+    if service_data.contains_key(&xiaomi_lywsd03mmc_atc::SERVICE_ID) {
+        return Ok(Some(xiaomi_lywsd03mmc_atc::DRIVER));
+    }
+    let uuids = device.uuids().await?.unwrap_or_default();
+    if uuids.contains(&xiaomi_miflora::SERVICE_ID) {
+        return Ok(Some(xiaomi_miflora::DRIVER));
+    }
+    let ibeacon = device
+        .manufacturer_data()
+        .await?
+        .and_then(|data| {
+            data.get(&beacon::APPLE_COMPANY_ID)
+                .and_then(|value| beacon::IBeacon::parse(value))
+        })
+        .is_some();
+    let eddystone = service_data
+        .get(&beacon::EDDYSTONE_SERVICE_ID)
+        .and_then(|value| beacon::Eddystone::parse(value))
+        .is_some();
+    if ibeacon || eddystone {
+        return Ok(Some(beacon::DRIVER));
+    }
+    Ok(None)
+}
+
+/// Decodes a hexadecimal payload like the given driver would, returns the named values
+pub fn decode(driver: &str, payload: &str) -> anyhow::Result<Vec<(String, String)>> {
+    let data = parse_hex(payload)?;
+    let format = |values: Vec<(&'static str, f64)>| {
+        values
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    };
+    match driver {
+        xiaomi_lywsd03mmc_atc::DRIVER => Ok(format(xiaomi_lywsd03mmc_atc::decode(&data))),
+        xiaomi_miflora::DRIVER => xiaomi_miflora::decode(&data).map(format),
+        beacon::DRIVER => {
+            if let Some(frame) = beacon::IBeacon::parse(&data) {
+                return Ok(vec![("ibeacon".into(), format!("{frame:?}"))]);
+            }
+            if let Some(frame) = beacon::Eddystone::parse(&data) {
+                return Ok(vec![("eddystone".into(), format!("{frame:?}"))]);
+            }
+            anyhow::bail!("payload is neither an ibeacon nor an eddystone frame")
+        }
+        other => anyhow::bail!(
+            "unknown driver {other:?}, expected one of {}",
+            DRIVERS.join(", ")
+        ),
+    }
+}
+
+/// Accepts bytes separated by spaces or colons, like `0a:1b` or `0a 1b`
+fn parse_hex(payload: &str) -> anyhow::Result<Vec<u8>> {
+    let digits: String = payload
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != ':')
+        .collect();
+    let digits = digits.strip_prefix("0x").unwrap_or(&digits);
+    if !digits.len().is_multiple_of(2) || !digits.is_ascii() {
+        anyhow::bail!("payload should be an even number of hexadecimal characters");
+    }
+    (0..digits.len())
+        .step_by(2)
+        .map(|index| {
+            u8::from_str_radix(&digits[index..index + 2], 16)
+                .with_context(|| format!("invalid byte {:?}", &digits[index..index + 2]))
+        })
+        .collect()
+}
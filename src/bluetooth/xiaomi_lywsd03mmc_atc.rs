@@ -1,31 +1,24 @@
-use opentelemetry::metrics::Gauge;
+use crate::metrics;
 
-const SERVICE_ID: uuid::Uuid = uuid::Uuid::from_u128(488837762788578050050668711589115);
+pub(crate) const DRIVER: &str = "xiaomi-lywsd03mmc-atc";
+
+pub(crate) const SERVICE_ID: uuid::Uuid = uuid::Uuid::from_u128(488837762788578050050668711589115);
 
 #[derive(Debug)]
 pub(crate) struct XiaomiLywsd03mmcAtcCollector {
-    temperature: Gauge<f64>,
-    humidity: Gauge<f64>,
-    battery: Gauge<f64>,
+    temperature: metrics::Gauge<f64>,
+    humidity: metrics::Gauge<f64>,
+    battery: metrics::Gauge<f64>,
 }
 
 impl Default for XiaomiLywsd03mmcAtcCollector {
     fn default() -> Self {
-        let meter = opentelemetry::global::meter("xiaomi-lywsd03mmc-atc");
+        let meter = opentelemetry::global::meter(DRIVER);
 
         Self {
-            temperature: meter
-                .f64_gauge("measurement.temperature")
-                .with_unit("degree celcius")
-                .build(),
-            humidity: meter
-                .f64_gauge("measurement.humidity")
-                .with_unit("percentage")
-                .build(),
-            battery: meter
-                .f64_gauge("system.battery")
-                .with_unit("percentage")
-                .build(),
+            temperature: metrics::TEMPERATURE.f64_gauge(&meter),
+            humidity: metrics::HUMIDITY.f64_gauge(&meter),
+            battery: metrics::BATTERY_LEVEL_PERCENTAGE.f64_gauge(&meter),
         }
     }
 }
@@ -55,6 +48,18 @@ impl XiaomiLywsd03mmcAtcCollector {
     }
 }
 
+/// Values of an advertised payload, without recording them
+pub(crate) fn decode(data: &[u8]) -> Vec<(&'static str, f64)> {
+    [
+        ("temperature", read_temperature(data)),
+        ("humidity", read_humidity(data)),
+        ("battery", read_battery(data)),
+    ]
+    .into_iter()
+    .filter_map(|(name, value)| value.map(|value| (name, value)))
+    .collect()
+}
+
 const TEMPERATURE_INDEX: usize = 6;
 const HUMIDITY_INDEX: usize = 8;
 const BATTERY_INDEX: usize = 9;
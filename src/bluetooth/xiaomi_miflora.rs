@@ -1,18 +1,28 @@
 use std::{
     borrow::Cow,
     collections::HashMap,
+    sync::Arc,
     time::{Duration, SystemTime},
 };
 
 use bluer::gatt::remote::CharacteristicWriteRequest;
 use opentelemetry::{
     KeyValue,
-    metrics::{Counter, Gauge, Meter},
+    metrics::{Counter, Meter},
 };
+use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 
-const SERVICE_ID: Uuid = Uuid::from_u128(0x0000fe95_0000_1000_8000_00805f9b34fb);
-const CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60 * 2); // 2h
+use super::devices::SharedDeviceSettings;
+use crate::{Supervisor, metrics};
+
+pub(crate) const DRIVER: &str = "xiaomi-miflora";
+
+pub(crate) const SERVICE_ID: Uuid = Uuid::from_u128(0x0000fe95_0000_1000_8000_00805f9b34fb);
+
+/// Time given to the device being read to finish when stopping
+const SESSION_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(2);
 
 type DiscoveredDevice = (bluer::Device, Vec<KeyValue>);
 
@@ -29,15 +39,9 @@ impl Sender {
     fn new(meter: &Meter, inner: tokio::sync::mpsc::Sender<DiscoveredDevice>) -> Self {
         Self {
             inner,
-            attributes: [KeyValue::new("topic", "xiaomi-miflora")],
-            sent: meter
-                .u64_counter("queue.events.sent")
-                .with_description("Number of events sent in the queue")
-                .build(),
-            error: meter
-                .u64_counter("queue.events.sent.error")
-                .with_description("Number of events that failed being sent in the queue")
-                .build(),
+            attributes: [KeyValue::new("topic", DRIVER)],
+            sent: metrics::QUEUE_EVENTS_SENT.u64_counter(meter),
+            error: metrics::QUEUE_EVENTS_SENT_ERROR.u64_counter(meter),
         }
     }
 
@@ -66,11 +70,8 @@ impl Receiver {
     fn new(meter: &Meter, inner: tokio::sync::mpsc::Receiver<DiscoveredDevice>) -> Self {
         Self {
             inner,
-            attributes: [KeyValue::new("topic", "xiaomi-miflora")],
-            received: meter
-                .u64_counter("queue.events.received")
-                .with_description("Number of events received from the queue")
-                .build(),
+            attributes: [KeyValue::new("topic", DRIVER)],
+            received: metrics::QUEUE_EVENTS_RECEIVED.u64_counter(meter),
         }
     }
 
@@ -83,13 +84,16 @@ impl Receiver {
 
 #[derive(Debug)]
 struct XiaomiMifloraRunner {
+    devices: SharedDeviceSettings,
     last_check: HashMap<bluer::Address, SystemTime>,
+    /// Devices connected to, disconnected when stopping
+    connected: HashMap<bluer::Address, bluer::Device>,
     receiver: Receiver,
-    temperature: Gauge<f64>,
-    brightness: Gauge<f64>,
-    moisture: Gauge<f64>,
-    conductivity: Gauge<f64>,
-    battery: Gauge<f64>,
+    temperature: metrics::Gauge<f64>,
+    brightness: metrics::Gauge<f64>,
+    moisture: metrics::Gauge<f64>,
+    conductivity: metrics::Gauge<f64>,
+    battery: metrics::Gauge<f64>,
 }
 
 impl XiaomiMifloraRunner {
@@ -111,19 +115,29 @@ impl XiaomiMifloraRunner {
     ) -> anyhow::Result<()> {
         let span = tracing::Span::current();
         let address = device.address();
+        // the identity resolved by the collector, when any
+        let identity = attributes
+            .iter()
+            .find(|kv| kv.key.as_str() == "identity")
+            .map(|kv| kv.value.as_str());
+        let interval = self
+            .devices
+            .current()
+            .poll_interval(address, identity.as_deref());
         if let Some(last) = self.last_check.get(&address)
-            && *last + CHECK_INTERVAL > SystemTime::now()
+            && *last + interval > SystemTime::now()
         {
             tracing::trace!(
                 message = "device checked recently, skipping",
                 address = %address,
                 last = ?last,
-                interval = ?CHECK_INTERVAL,
+                interval = ?interval,
             );
             span.record("otel.status_code", "OK");
             return Ok(());
         }
 
+        self.connected.insert(address, device.clone());
         device.connect().await?;
 
         let device = MifloraDevice::new(device).await?;
@@ -145,64 +159,117 @@ impl XiaomiMifloraRunner {
         Ok(())
     }
 
-    async fn run(mut self) -> anyhow::Result<()> {
-        while let Some((device, attributes)) = self.receiver.recv().await {
-            if let Err(err) = self.handle_device(device, attributes).await {
-                tracing::error!(
-                    message = "unable to handle device",
-                    exception.message = err.to_string(),
-                    exception.stacktrace = format!("{err:?}"),
-                );
+    /// Reads the queued devices until stopped, the device being read is given
+    /// [`SESSION_SHUTDOWN_TIMEOUT`] to finish.
+    async fn run(&mut self, stop: &CancellationToken) -> anyhow::Result<()> {
+        loop {
+            let (device, attributes) = tokio::select! {
+                biased;
+                _ = stop.cancelled() => break,
+                event = self.receiver.recv() => match event {
+                    Some(event) => event,
+                    None => break,
+                },
+            };
+            let deadline = async {
+                stop.cancelled().await;
+                tokio::time::sleep(SESSION_SHUTDOWN_TIMEOUT).await;
+            };
+            tokio::select! {
+                result = self.handle_device(device, attributes) => {
+                    if let Err(err) = result {
+                        tracing::error!(
+                            message = "unable to handle device",
+                            exception.message = err.to_string(),
+                            exception.stacktrace = format!("{err:?}"),
+                        );
+                    }
+                }
+                _ = deadline => {
+                    tracing::warn!(message = "aborting device session, stopping took too long");
+                    break;
+                }
             }
         }
         Ok(())
     }
+
+    async fn disconnect(&mut self) {
+        for (address, device) in self.connected.drain() {
+            if !device.is_connected().await.unwrap_or(false) {
+                continue;
+            }
+            match tokio::time::timeout(DISCONNECT_TIMEOUT, device.disconnect()).await {
+                Ok(Ok(())) => tracing::debug!(message = "device disconnected", address = %address),
+                Ok(Err(err)) => tracing::warn!(
+                    message = "unable to disconnect device",
+                    address = %address,
+                    error.message = err.to_string(),
+                ),
+                Err(_) => tracing::warn!(
+                    message = "unable to disconnect device",
+                    address = %address,
+                    error.message = "timeout",
+                ),
+            }
+        }
+    }
 }
 
 #[derive(Debug)]
 pub(crate) struct XiaomiMifloraCollector {
     sender: Sender,
-    #[allow(unused)]
-    task: tokio::task::JoinHandle<anyhow::Result<()>>,
+    runner: Arc<tokio::sync::Mutex<XiaomiMifloraRunner>>,
+    stop: CancellationToken,
 }
 
-impl Default for XiaomiMifloraCollector {
-    fn default() -> Self {
-        let meter = opentelemetry::global::meter("xiaomi-miflora");
+impl XiaomiMifloraCollector {
+    pub(crate) fn new(devices: SharedDeviceSettings, supervisor: &Supervisor) -> Self {
+        let meter = opentelemetry::global::meter(DRIVER);
 
         let (sender, receiver) = tokio::sync::mpsc::channel(1024);
 
         let runner = XiaomiMifloraRunner {
+            devices,
             last_check: Default::default(),
+            connected: Default::default(),
             receiver: Receiver::new(&meter, receiver),
-            temperature: meter
-                .f64_gauge("measurement.temperature")
-                .with_unit("degree celcius")
-                .build(),
-            brightness: meter
-                .f64_gauge("measurement.brightness")
-                .with_unit("lux")
-                .build(),
-            moisture: meter
-                .f64_gauge("measurement.moisture")
-                .with_unit("percent")
-                .build(),
-            conductivity: meter.f64_gauge("measurement.conductivity").build(),
-            battery: meter
-                .f64_gauge("system.battery")
-                .with_unit("percent")
-                .build(),
+            temperature: metrics::TEMPERATURE.f64_gauge(&meter),
+            brightness: metrics::ILLUMINANCE.f64_gauge(&meter),
+            moisture: metrics::SOIL_MOISTURE.f64_gauge(&meter),
+            conductivity: metrics::SOIL_CONDUCTIVITY.f64_gauge(&meter),
+            battery: metrics::BATTERY_LEVEL.f64_gauge(&meter),
         };
-        let task = tokio::spawn(runner.run());
+        // kept between restarts so that the queued devices aren't lost
+        let runner = Arc::new(tokio::sync::Mutex::new(runner));
+        // stopped by the collector once the discovery stopped, not with the other tasks
+        let stop = CancellationToken::new();
+        supervisor.spawn("xiaomi-miflora.runner", {
+            let runner = runner.clone();
+            let stop = stop.clone();
+            move |_| {
+                let runner = runner.clone();
+                let stop = stop.clone();
+                async move { runner.lock().await.run(&stop).await }
+            }
+        });
 
         Self {
             sender: Sender::new(&meter, sender),
-            task,
+            runner,
+            stop,
         }
     }
-}
 
-impl XiaomiMifloraCollector {
+    /// Stops reading the queued devices, waits for the device being read then disconnects
+    /// the devices connected to.
+    pub(crate) async fn shutdown(self) {
+        self.stop.cancel();
+        drop(self.sender);
+        // released by the runner once it stopped
+        self.runner.lock().await.disconnect().await;
+    }
+
     pub async fn collect(
         &self,
         device: &bluer::Device,
@@ -221,6 +288,25 @@ impl XiaomiMifloraCollector {
     }
 }
 
+/// Values of a realtime payload, as read from the data characteristic
+pub(crate) fn decode(data: &[u8]) -> anyhow::Result<Vec<(&'static str, f64)>> {
+    if data.len() < REALTIME_LENGTH {
+        anyhow::bail!(
+            "expected at least {REALTIME_LENGTH} bytes, found {}",
+            data.len()
+        );
+    }
+    let entry = MifloraRealtimeEntry {
+        inner: data.to_vec(),
+    };
+    Ok(vec![
+        ("temperature", entry.temperature()),
+        ("brightness", entry.brightness()),
+        ("moisture", entry.moisture()),
+        ("conductivity", entry.conductivity()),
+    ])
+}
+
 struct MifloraSystem {
     inner: Vec<u8>,
 }
@@ -235,6 +321,9 @@ impl MifloraSystem {
     }
 }
 
+/// Bytes used by the realtime values
+const REALTIME_LENGTH: usize = 10;
+
 struct MifloraRealtimeEntry {
     inner: Vec<u8>,
 }
@@ -0,0 +1,467 @@
+//! Configuration values, read from the environment or from the TOML configuration file.
+//!
+//! Every setting has an environment variable and a key in the file, the variable wins when
+//! both are set. Values from the file are parsed like the variables so both behave the same.
+//!
+//! Invalid settings don't stop the reading, they are recorded by the [`Source`] and replaced by
+//! their default so that every problem is reported at once by [`Source::check`]. The keys of
+//! the file no setting read, like misspelled ones, are reported too.
+
+use std::{
+    cell::RefCell,
+    collections::HashSet,
+    fmt::Display,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// Read when no path is given, it's fine for it to be missing
+pub const DEFAULT_PATH: &str = "/etc/myhomelab/config.toml";
+
+/// Used when the `STATE_DIRECTORY` set by systemd is missing
+const DEFAULT_STATE_DIRECTORY: &str = "/var/lib/myhomelab";
+
+/// Directory for the data kept between restarts, given by systemd
+pub(crate) fn state_directory() -> PathBuf {
+    std::env::var_os("STATE_DIRECTORY")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_STATE_DIRECTORY))
+}
+
+/// Problem found with a single setting
+#[derive(Debug)]
+pub struct FieldError {
+    /// Variable or key of the file, along with the path of the file
+    pub setting: String,
+    pub message: String,
+}
+
+impl std::fmt::Display for FieldError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.setting, self.message)
+    }
+}
+
+/// Every invalid setting found while reading the configuration
+#[derive(Debug)]
+pub struct ConfigError {
+    pub errors: Vec<FieldError>,
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.errors.as_slice() {
+            [error] => write!(f, "{error}"),
+            errors => {
+                write!(f, "{} invalid settings", errors.len())?;
+                errors
+                    .iter()
+                    .try_for_each(|error| write!(f, "\n  - {error}"))
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[derive(Debug, Default)]
+pub struct Source {
+    path: Option<PathBuf>,
+    table: toml::Table,
+    errors: RefCell<Vec<FieldError>>,
+    /// Keys of the settings read, along with everything below them
+    known: RefCell<HashSet<String>>,
+}
+
+/// Where a value comes from, to point at it in the error messages
+enum Origin<'a> {
+    Env(&'a str),
+    File(&'a str, &'a Path),
+}
+
+impl std::fmt::Display for Origin<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Env(name) => write!(f, "{name}"),
+            Self::File(key, path) => write!(f, "{key} in {}", path.display()),
+        }
+    }
+}
+
+impl Source {
+    /// Only reads the environment variables
+    pub fn env() -> Self {
+        Self::default()
+    }
+
+    /// Reads the configuration file, the default one is only read when it exists
+    pub fn load(path: Option<&Path>) -> Result<Self, ConfigError> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None if Path::new(DEFAULT_PATH).exists() => PathBuf::from(DEFAULT_PATH),
+            None => return Ok(Self::env()),
+        };
+        let fail = |message: String| ConfigError {
+            errors: vec![FieldError {
+                setting: path.display().to_string(),
+                message,
+            }],
+        };
+        let content = std::fs::read_to_string(&path)
+            .map_err(|err| fail(format!("unable to read configuration file: {err}")))?;
+        let table = content
+            .parse::<toml::Table>()
+            .map_err(|err| fail(format!("invalid configuration file: {err}")))?;
+        Ok(Self {
+            path: Some(path),
+            table,
+            ..Default::default()
+        })
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Returns the invalid settings found since the last check
+    pub fn check(&self) -> Result<(), ConfigError> {
+        let errors = self.errors.take();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError { errors })
+        }
+    }
+
+    /// Reports the keys of the file that don't belong to any setting, once every setting has
+    /// been read
+    pub(crate) fn report_unknown_keys(&self) {
+        let Some(path) = self.path.as_deref() else {
+            return;
+        };
+        let mut unknown = Vec::new();
+        unknown_keys(&self.table, None, &self.known.borrow(), &mut unknown);
+        for (key, message) in unknown {
+            self.record(&Origin::File(&key, path), message);
+        }
+    }
+
+    /// Accepts the keys below this one without reading them, like the settings of a feature
+    /// disabled at build time
+    #[cfg_attr(all(feature = "bluetooth", feature = "storage"), allow(dead_code))]
+    pub(crate) fn accept(&self, key: &str) {
+        self.known.borrow_mut().insert(key.to_string());
+    }
+
+    /// Records a problem found when validating a setting
+    pub(crate) fn report(&self, env: &str, key: &str, message: impl Display) {
+        self.record(&self.origin(env, key), message);
+    }
+
+    fn record(&self, origin: &Origin<'_>, message: impl Display) {
+        self.errors.borrow_mut().push(FieldError {
+            setting: origin.to_string(),
+            message: format!("{message:#}"),
+        });
+    }
+
+    /// Where a setting is read from, the variable when neither is set
+    fn origin<'a>(&'a self, env: &'a str, key: &'a str) -> Origin<'a> {
+        match self.path.as_deref() {
+            Some(path) if std::env::var_os(env).is_none() => Origin::File(key, path),
+            _ => Origin::Env(env),
+        }
+    }
+
+    /// Looks for a dotted key like `bluetooth.rssi.window`
+    fn lookup<'a>(&'a self, key: &'a str) -> Option<(&'a toml::Value, Origin<'a>)> {
+        let path = self.path.as_deref()?;
+        self.accept(key);
+        let (parents, last) = match key.rsplit_once('.') {
+            Some((parents, last)) => (Some(parents), last),
+            None => (None, key),
+        };
+        let mut table = &self.table;
+        for part in parents.into_iter().flat_map(|parents| parents.split('.')) {
+            table = table.get(part)?.as_table()?;
+        }
+        Some((table.get(last)?, Origin::File(key, path)))
+    }
+
+    /// Raw value of a setting
+    pub(crate) fn string(&self, env: &str, key: &str) -> Option<String> {
+        self.raw(env, key).map(|(value, _)| value)
+    }
+
+    fn raw<'a>(&'a self, env: &'a str, key: &'a str) -> Option<(String, Origin<'a>)> {
+        if let Ok(value) = std::env::var(env) {
+            self.accept(key);
+            return Some((value, Origin::Env(env)));
+        }
+        let (value, origin) = self.lookup(key)?;
+        match scalar(value) {
+            Ok(value) => Some((value, origin)),
+            Err(err) => {
+                self.record(&origin, err);
+                None
+            }
+        }
+    }
+
+    /// Parses a setting, the error names the variable or the key of the file
+    pub(crate) fn parse<T>(&self, env: &str, key: &str) -> Option<T>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        let (value, origin) = self.raw(env, key)?;
+        match value.parse::<T>() {
+            Ok(value) => Some(value),
+            Err(err) => {
+                self.record(&origin, err);
+                None
+            }
+        }
+    }
+
+    /// List of items, comma separated in the variable.
+    ///
+    /// In the file, it can be an array or a table whose entries become `key=value` items.
+    pub(crate) fn list(&self, env: &str, key: &str) -> Option<Vec<String>> {
+        if let Ok(value) = std::env::var(env) {
+            self.accept(key);
+            return Some(
+                value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|item| !item.is_empty())
+                    .map(String::from)
+                    .collect(),
+            );
+        }
+        let (value, origin) = self.lookup(key)?;
+        let items = match value {
+            toml::Value::Array(items) => items.iter().map(scalar).collect(),
+            toml::Value::Table(table) => table
+                .iter()
+                .map(|(key, value)| scalar(value).map(|value| format!("{key}={value}")))
+                .collect(),
+            other => scalar(other).map(|value| vec![value]),
+        };
+        match items {
+            Ok(items) => Some(items),
+            Err(err) => {
+                self.record(&origin, err);
+                None
+            }
+        }
+    }
+
+    /// Parses every item of a list, the invalid items are reported and skipped
+    pub(crate) fn parse_list<T>(&self, env: &str, key: &str) -> Option<Vec<T>>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        let items = self.list(env, key)?;
+        let origin = self.origin(env, key);
+        Some(
+            items
+                .iter()
+                .enumerate()
+                .filter_map(|(index, item)| match item.parse::<T>() {
+                    Ok(value) => Some(value),
+                    Err(err) => {
+                        // items can hold secrets, they are only referred to by position
+                        self.record(&origin, format_args!("item {}: {err:#}", index + 1));
+                        None
+                    }
+                })
+                .collect(),
+        )
+    }
+}
+
+/// Collects the keys not read by any setting, and the ones read as tables but holding a value
+fn unknown_keys(
+    table: &toml::Table,
+    parent: Option<&str>,
+    known: &HashSet<String>,
+    unknown: &mut Vec<(String, String)>,
+) {
+    for (name, value) in table {
+        let key = match parent {
+            Some(parent) => format!("{parent}.{name}"),
+            None => name.clone(),
+        };
+        if known.contains(&key) {
+            continue;
+        }
+        let prefix = format!("{key}.");
+        let is_parent = known.iter().any(|item| item.starts_with(&prefix));
+        match value {
+            toml::Value::Table(table) if is_parent => {
+                unknown_keys(table, Some(&key), known, unknown)
+            }
+            other if is_parent => {
+                unknown.push((key, format!("expected a table, found {}", other.type_str())))
+            }
+            _ => unknown.push((key, "unknown setting".to_string())),
+        }
+    }
+}
+
+fn scalar(value: &toml::Value) -> anyhow::Result<String> {
+    match value {
+        toml::Value::String(value) => Ok(value.clone()),
+        toml::Value::Integer(value) => Ok(value.to_string()),
+        toml::Value::Float(value) => Ok(value.to_string()),
+        toml::Value::Boolean(value) => Ok(value.to_string()),
+        other => Err(anyhow::anyhow!(
+            "expected a string, a number or a boolean, found {}",
+            other.type_str()
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(content: &str) -> Source {
+        Source {
+            path: Some(PathBuf::from("config.toml")),
+            table: content.parse().unwrap(),
+            ..Default::default()
+        }
+    }
+
+    fn messages(source: &Source) -> Vec<String> {
+        match source.check() {
+            Ok(()) => Vec::new(),
+            Err(err) => err.errors.iter().map(ToString::to_string).collect(),
+        }
+    }
+
+    #[test]
+    fn parses_values_like_the_variables() {
+        let source = source(
+            r#"
+            [health]
+            listen_address = "127.0.0.1:8080"
+            max_event_age = 60
+            [bluetooth.rssi]
+            window = "5"
+            "#,
+        );
+        let address: Option<std::net::SocketAddr> =
+            source.parse("MYHOMELAB_TEST_ADDRESS", "health.listen_address");
+        assert_eq!(address, Some("127.0.0.1:8080".parse().unwrap()));
+        assert_eq!(
+            source.parse::<u64>("MYHOMELAB_TEST_AGE", "health.max_event_age"),
+            Some(60)
+        );
+        assert_eq!(
+            source.parse::<usize>("MYHOMELAB_TEST_WINDOW", "bluetooth.rssi.window"),
+            Some(5)
+        );
+        assert_eq!(
+            source.parse::<u64>("MYHOMELAB_TEST_MISSING", "bluetooth.missing"),
+            None
+        );
+        assert!(messages(&source).is_empty());
+    }
+
+    #[test]
+    fn reads_lists_from_arrays_and_tables() {
+        let source = source(
+            r#"
+            allow = ["a", "b"]
+            [aliases]
+            kitchen = "A4:C1:38:00:00:01"
+            "#,
+        );
+        assert_eq!(
+            source.list("MYHOMELAB_TEST_ALLOW", "allow"),
+            Some(vec!["a".to_string(), "b".to_string()])
+        );
+        assert_eq!(
+            source.list("MYHOMELAB_TEST_ALIASES", "aliases"),
+            Some(vec!["kitchen=A4:C1:38:00:00:01".to_string()])
+        );
+    }
+
+    #[test]
+    fn collects_every_error() {
+        let source = source(
+            r#"
+            window = "ten"
+            address = [1]
+            intervals = [1, "two", 3]
+            "#,
+        );
+        assert_eq!(
+            source.parse::<usize>("MYHOMELAB_TEST_WINDOW", "window"),
+            None
+        );
+        assert_eq!(source.string("MYHOMELAB_TEST_ADDRESS", "address"), None);
+        assert_eq!(
+            source.parse_list::<u64>("MYHOMELAB_TEST_INTERVALS", "intervals"),
+            Some(vec![1, 3])
+        );
+        assert_eq!(
+            messages(&source),
+            [
+                "window in config.toml: invalid digit found in string",
+                "address in config.toml: expected a string, a number or a boolean, found array",
+                "intervals in config.toml: item 2: invalid digit found in string",
+            ]
+        );
+        // the errors are only returned once
+        assert!(source.check().is_ok());
+    }
+
+    #[test]
+    fn formats_the_errors() {
+        let error = |setting: &str| FieldError {
+            setting: setting.to_string(),
+            message: "invalid".to_string(),
+        };
+        let single = ConfigError {
+            errors: vec![error("A")],
+        };
+        assert_eq!(single.to_string(), "A: invalid");
+        let several = ConfigError {
+            errors: vec![error("A"), error("B")],
+        };
+        assert_eq!(
+            several.to_string(),
+            "2 invalid settings\n  - A: invalid\n  - B: invalid"
+        );
+    }
+
+    #[test]
+    fn reports_unknown_keys() {
+        let source = source(
+            r#"
+            bluetooth = { rssi = { window = 5, windwo = 3 } }
+            typo = true
+            storage = 1
+            [telemetry]
+            anything = "below an accepted key"
+            "#,
+        );
+        source.parse::<usize>("MYHOMELAB_TEST_WINDOW", "bluetooth.rssi.window");
+        source.parse::<String>("MYHOMELAB_TEST_PATH", "storage.path");
+        source.accept("telemetry");
+        source.report_unknown_keys();
+        assert_eq!(
+            messages(&source),
+            [
+                "bluetooth.rssi.windwo in config.toml: unknown setting",
+                "storage in config.toml: expected a table, found integer",
+                "typo in config.toml: unknown setting",
+            ]
+        );
+    }
+}
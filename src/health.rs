@@ -0,0 +1,273 @@
+//! State of the collectors, served over HTTP for the orchestrators and uptime monitors.
+//!
+//! `/healthz` fails when a component is stuck or dead and the process should be restarted,
+//! `/readyz` also fails while the adapter isn't scanning, a failed task waits to be restarted or
+//! the exports are failing.
+//! The collectors can serve their own routes next to them, like the bluetooth `/inventory`.
+#![cfg_attr(not(feature = "bluetooth"), allow(dead_code))]
+
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{config::Source, http::HttpServer};
+
+/// Time without events after which a component is considered stuck
+const DEFAULT_MAX_EVENT_AGE: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug)]
+pub(crate) struct HealthConfig {
+    address: Option<SocketAddr>,
+    max_event_age: Duration,
+}
+
+impl crate::Configurable for HealthConfig {
+    fn from_source(source: &Source) -> Self {
+        Self {
+            address: source.parse("HEALTH_LISTEN_ADDRESS", "health.listen_address"),
+            max_event_age: source
+                .parse("HEALTH_MAX_EVENT_AGE", "health.max_event_age")
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_MAX_EVENT_AGE),
+        }
+    }
+}
+
+impl HealthConfig {
+    pub(crate) fn build(&self) -> Health {
+        Health {
+            components: Default::default(),
+            max_event_age: self.max_event_age,
+        }
+    }
+
+    /// Creates the server for the probes and the given routes, when a listen address is set
+    pub(crate) fn install(
+        &self,
+        health: &Health,
+        routes: axum::Router,
+    ) -> anyhow::Result<Option<HttpServer>> {
+        let Some(address) = self.address else {
+            return Ok(None);
+        };
+        let router = axum::Router::new()
+            .route("/healthz", axum::routing::get(handle_healthz))
+            .route("/readyz", axum::routing::get(handle_readyz))
+            .with_state(health.clone())
+            .merge(routes);
+        HttpServer::bind("health-server", address, router).map(Some)
+    }
+}
+
+async fn handle_healthz(
+    axum::extract::State(health): axum::extract::State<Health>,
+) -> impl axum::response::IntoResponse {
+    health.respond(Probe::Liveness)
+}
+
+async fn handle_readyz(
+    axum::extract::State(health): axum::extract::State<Health>,
+) -> impl axum::response::IntoResponse {
+    health.respond(Probe::Readiness)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Probe {
+    Liveness,
+    Readiness,
+}
+
+#[derive(Debug)]
+enum Component {
+    /// Condition reported by the component, like the adapter being powered
+    Ready(bool),
+    /// Background task, alive until its guard is dropped
+    Task(TaskState),
+    /// Last event handled, stale after the maximum event age
+    Activity(Instant),
+    /// Outcome of the recent attempts, like the exports to the collector
+    Outcome {
+        errors: u64,
+        last_error: Option<String>,
+        failing: bool,
+    },
+}
+
+impl Component {
+    fn probe(&self) -> Probe {
+        match self {
+            // a task waiting to be restarted isn't ready, but restarting the process won't help
+            Self::Ready(_) | Self::Outcome { .. } | Self::Task(TaskState::Restarting) => {
+                Probe::Readiness
+            }
+            Self::Task(_) | Self::Activity(_) => Probe::Liveness,
+        }
+    }
+
+    fn report(&self, max_event_age: Duration) -> (bool, serde_json::Value) {
+        match self {
+            Self::Ready(ready) => (*ready, serde_json::json!({ "ready": ready })),
+            Self::Task(state) => (
+                *state == TaskState::Running,
+                serde_json::json!({ "alive": *state != TaskState::Dead, "state": state.name() }),
+            ),
+            Self::Activity(at) => {
+                let age = at.elapsed();
+                (
+                    age <= max_event_age,
+                    serde_json::json!({ "last_event_age_seconds": age.as_secs() }),
+                )
+            }
+            Self::Outcome {
+                errors,
+                last_error,
+                failing,
+            } => (
+                !failing,
+                serde_json::json!({ "errors": errors, "last_error": last_error }),
+            ),
+        }
+    }
+}
+
+/// State of every component, shared with the endpoints
+#[derive(Clone, Debug)]
+pub(crate) struct Health {
+    components: Arc<Mutex<BTreeMap<&'static str, Component>>>,
+    max_event_age: Duration,
+}
+
+impl Health {
+    fn update(&self, name: &'static str, component: Component) {
+        self.components
+            .lock()
+            .expect("health lock poisoned")
+            .insert(name, component);
+    }
+
+    /// Records a condition needed for the process to be ready
+    pub(crate) fn set_ready(&self, name: &'static str, ready: bool) {
+        self.update(name, Component::Ready(ready));
+    }
+
+    /// Records that the component just handled an event
+    pub(crate) fn touch(&self, name: &'static str) {
+        self.update(name, Component::Activity(Instant::now()));
+    }
+
+    /// Marks a task alive until the returned guard is dropped, even when it panics
+    pub(crate) fn task(&self, name: &'static str) -> TaskGuard {
+        self.update(name, Component::Task(TaskState::Running));
+        TaskGuard {
+            health: self.clone(),
+            name,
+        }
+    }
+
+    /// Records the outcome of an attempt, the component fails until the next success
+    pub(crate) fn record<E: std::fmt::Display>(&self, name: &'static str, result: &Result<(), E>) {
+        let mut components = self.components.lock().expect("health lock poisoned");
+        let (errors, last_error) = match components.remove(name) {
+            Some(Component::Outcome {
+                errors, last_error, ..
+            }) => (errors, last_error),
+            _ => (0, None),
+        };
+        let component = match result {
+            Ok(()) => Component::Outcome {
+                errors,
+                last_error,
+                failing: false,
+            },
+            Err(err) => Component::Outcome {
+                errors: errors + 1,
+                last_error: Some(err.to_string()),
+                failing: true,
+            },
+        };
+        components.insert(name, component);
+    }
+
+    fn respond(&self, probe: Probe) -> impl axum::response::IntoResponse + use<> {
+        let components = self.components.lock().expect("health lock poisoned");
+        let mut healthy = true;
+        let checks = components
+            .iter()
+            .map(|(name, component)| {
+                let (ok, mut details) = component.report(self.max_event_age);
+                // the readiness also requires the components to be alive
+                if probe == Probe::Readiness || component.probe() == Probe::Liveness {
+                    healthy &= ok;
+                }
+                details["status"] = status(ok).into();
+                (name.to_string(), details)
+            })
+            .collect::<serde_json::Map<_, _>>();
+        let code = if healthy {
+            axum::http::StatusCode::OK
+        } else {
+            axum::http::StatusCode::SERVICE_UNAVAILABLE
+        };
+        let body = serde_json::json!({ "status": status(healthy), "checks": checks });
+        (
+            code,
+            [(axum::http::header::CONTENT_TYPE, "application/json")],
+            body.to_string(),
+        )
+    }
+}
+
+fn status(ok: bool) -> &'static str {
+    if ok { "ok" } else { "failing" }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum TaskState {
+    Running,
+    /// Failed and waiting for the backoff before being started again
+    Restarting,
+    Dead,
+}
+
+impl TaskState {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Running => "running",
+            Self::Restarting => "restarting",
+            Self::Dead => "dead",
+        }
+    }
+}
+
+/// Held by a background task, marks it dead when dropped
+#[derive(Debug)]
+pub(crate) struct TaskGuard {
+    health: Health,
+    name: &'static str,
+}
+
+impl TaskGuard {
+    /// Marks the task as running again, once restarted
+    pub(crate) fn running(&self) {
+        self.health
+            .update(self.name, Component::Task(TaskState::Running));
+    }
+
+    /// Marks the task as failed but about to be restarted
+    pub(crate) fn restarting(&self) {
+        self.health
+            .update(self.name, Component::Task(TaskState::Restarting));
+    }
+}
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        // can run while unwinding, a poisoned lock must not panic again
+        if let Ok(mut components) = self.health.components.lock() {
+            components.insert(self.name, Component::Task(TaskState::Dead));
+        }
+    }
+}
@@ -0,0 +1,49 @@
+//! HTTP endpoints, bound when building the application and served by the supervisor.
+
+use std::net::SocketAddr;
+
+use anyhow::Context;
+
+#[derive(Debug)]
+pub(crate) struct HttpServer {
+    name: &'static str,
+    /// Cloned every time the server is started, so that it can be restarted
+    listener: std::net::TcpListener,
+    router: axum::Router,
+}
+
+impl HttpServer {
+    /// Binds the address right away, for the application to fail early when it's taken
+    pub(crate) fn bind(
+        name: &'static str,
+        address: SocketAddr,
+        router: axum::Router,
+    ) -> anyhow::Result<Self> {
+        let listener = std::net::TcpListener::bind(address)
+            .with_context(|| format!("unable to bind {name} on {address}"))?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            name,
+            listener,
+            router,
+        })
+    }
+
+    /// Serves the endpoints until the application stops, letting the pending requests end
+    pub(crate) fn spawn(self, supervisor: &crate::Supervisor) {
+        if let Ok(address) = self.listener.local_addr() {
+            tracing::info!(message = "serving http endpoints", server = self.name, address = %address);
+        }
+        supervisor.spawn(self.name, move |token| {
+            let listener = self.listener.try_clone();
+            let router = self.router.clone();
+            async move {
+                let listener = tokio::net::TcpListener::from_std(listener?)?;
+                axum::serve(listener, router)
+                    .with_graceful_shutdown(token.cancelled_owned())
+                    .await?;
+                Ok(())
+            }
+        });
+    }
+}
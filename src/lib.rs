@@ -1,39 +1,138 @@
-use tokio_util::sync::CancellationToken;
+use std::{
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use opentelemetry::KeyValue;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
 
 #[cfg(feature = "bluetooth")]
 mod bluetooth;
+pub mod config;
+mod health;
+mod http;
+mod metrics;
 mod otel;
+#[cfg(feature = "storage")]
+mod storage;
+mod systemd;
+
+#[cfg(feature = "bluetooth")]
+pub use crate::bluetooth::tools::{DRIVERS, ScannedDevice, decode, scan};
+
+/// Maximum time spent exporting the buffered telemetry when exiting
+const TELEMETRY_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Time given to the background tasks to stop once the collectors stopped
+const TASKS_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Delay before restarting a failed task, doubled after each failure
+const RESTART_MIN_BACKOFF: Duration = Duration::from_secs(1);
+const RESTART_MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
 
 pub trait Configurable: Sized {
-    fn from_env() -> anyhow::Result<Self>;
+    /// Reads the settings, the invalid ones are reported to the source and replaced by their
+    /// default.
+    fn from_source(source: &config::Source) -> Self;
+
+    /// Reads the settings and fails with every invalid one
+    fn load(source: &config::Source) -> Result<Self, config::ConfigError> {
+        let value = Self::from_source(source);
+        source.check()?;
+        Ok(value)
+    }
+
+    fn from_env() -> Result<Self, config::ConfigError> {
+        Self::load(&config::Source::env())
+    }
 }
 
+#[derive(Debug)]
 pub struct ApplicationConfig {
+    /// Configuration file read again when reloading
+    path: Option<PathBuf>,
     otel: crate::otel::OtelConfig,
+    metrics: crate::metrics::MetricsConfig,
+    health: crate::health::HealthConfig,
+    #[cfg(feature = "storage")]
+    storage: crate::storage::StorageConfig,
     #[cfg(feature = "bluetooth")]
     bluetooth: crate::bluetooth::BluetoothConfig,
 }
 
 impl Configurable for ApplicationConfig {
-    fn from_env() -> anyhow::Result<Self> {
-        Ok(Self {
-            otel: crate::otel::OtelConfig::from_env()?,
+    fn from_source(source: &config::Source) -> Self {
+        Self {
+            path: source.path().map(PathBuf::from),
+            otel: crate::otel::OtelConfig::from_source(source),
+            metrics: crate::metrics::MetricsConfig::from_source(source),
+            health: crate::health::HealthConfig::from_source(source),
+            #[cfg(feature = "storage")]
+            storage: crate::storage::StorageConfig::from_source(source),
             #[cfg(feature = "bluetooth")]
-            bluetooth: crate::bluetooth::BluetoothConfig::from_env()?,
-        })
+            bluetooth: crate::bluetooth::BluetoothConfig::from_source(source),
+        }
+    }
+
+    /// Also fails with the keys of the file that don't belong to any setting
+    fn load(source: &config::Source) -> Result<Self, config::ConfigError> {
+        let value = Self::from_source(source);
+        // the settings of the disabled features are accepted, to share a file between builds
+        #[cfg(not(feature = "storage"))]
+        source.accept("storage");
+        #[cfg(not(feature = "bluetooth"))]
+        source.accept("bluetooth");
+        source.report_unknown_keys();
+        source.check()?;
+        Ok(value)
     }
 }
 
 impl ApplicationConfig {
     pub async fn build(&self) -> anyhow::Result<Application> {
-        self.otel.install()?;
+        // registered first, the default action of SIGHUP would kill the process while starting
+        let hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+            .context("unable to install the reload signal handler")?;
+        let health = self.health.build();
+        let (telemetry, prometheus) = self.otel.install(&health)?;
+        self.metrics.install();
+        #[cfg(feature = "storage")]
+        self.storage.install()?;
 
         let cancel_token = CancellationToken::new();
+        let supervisor = Supervisor::new(cancel_token.clone(), &health);
+
+        #[cfg(feature = "bluetooth")]
+        let bluetooth = self
+            .bluetooth
+            .build(cancel_token.child_token(), &health, &supervisor)
+            .await?;
+        #[cfg(feature = "bluetooth")]
+        let routes = bluetooth.router();
+        #[cfg(not(feature = "bluetooth"))]
+        let routes = axum::Router::new();
+        let servers = self
+            .health
+            .install(&health, routes)?
+            .into_iter()
+            .chain(prometheus)
+            .collect();
 
         Ok(Application {
             #[cfg(feature = "bluetooth")]
-            bluetooth: self.bluetooth.build(cancel_token.child_token()).await?,
+            bluetooth,
+            reloader: Reloader {
+                path: self.path.clone(),
+                hangup: tokio::sync::Mutex::new(hangup),
+                #[cfg(feature = "bluetooth")]
+                devices: self.bluetooth.devices(),
+            },
+            servers,
+            supervisor,
             cancel_token,
+            telemetry: Some(telemetry),
         })
     }
 }
@@ -41,18 +140,178 @@ impl ApplicationConfig {
 pub struct Application {
     #[cfg(feature = "bluetooth")]
     bluetooth: crate::bluetooth::BluetoothCollector,
+    reloader: Reloader,
+    servers: Vec<crate::http::HttpServer>,
+    supervisor: Supervisor,
     cancel_token: CancellationToken,
+    telemetry: Option<crate::otel::Telemetry>,
 }
 
 impl Application {
-    #[tracing::instrument(skip(self), err(Debug))]
-    pub async fn run(self) -> anyhow::Result<()> {
+    pub async fn run(mut self) -> anyhow::Result<()> {
+        let telemetry = self.telemetry.take();
+        let result = self.collect().await;
+        #[cfg(feature = "storage")]
+        tokio::task::spawn_blocking(crate::storage::close).await?;
+        // the span of `collect` must be closed for it to be exported
+        if let Some(telemetry) = telemetry {
+            tokio::task::spawn_blocking(move || telemetry.shutdown(TELEMETRY_SHUTDOWN_TIMEOUT))
+                .await?;
+        }
+        result
+    }
+
+    #[tracing::instrument(name = "run", skip(self), err(Debug))]
+    async fn collect(self) -> anyhow::Result<()> {
         tracing::info!("starting");
-        tokio::spawn(shutdown_signal(self.cancel_token));
+        for server in self.servers {
+            server.spawn(&self.supervisor);
+        }
+        let reloader = Arc::new(self.reloader);
+        self.supervisor.spawn("reloader", move |token| {
+            let reloader = reloader.clone();
+            async move {
+                reloader.run(token).await;
+                Ok(())
+            }
+        });
+        let cancel_token = self.cancel_token.clone();
+        self.supervisor.spawn("shutdown-signal", move |_| {
+            let cancel_token = cancel_token.clone();
+            async move {
+                shutdown_signal(cancel_token).await;
+                Ok(())
+            }
+        });
+        // the collectors stop their own drivers before the other tasks are stopped
         #[cfg(feature = "bluetooth")]
-        self.bluetooth.run().await?;
+        let result = self.bluetooth.run().await;
+        #[cfg(not(feature = "bluetooth"))]
+        let result = Ok(());
+        self.cancel_token.cancel();
+        self.supervisor.shutdown(TASKS_SHUTDOWN_TIMEOUT).await;
+        tracing::info!("stopped");
+        result
+    }
+}
+
+/// Applies the settings that don't require a restart, when receiving SIGHUP.
+///
+/// Only the device aliases, allow and deny lists and poll intervals are reloaded, the other
+/// settings, like the presence devices and timeouts, are kept until the next restart.
+struct Reloader {
+    path: Option<PathBuf>,
+    /// Shared with the restarted task, the signals received meanwhile aren't lost
+    hangup: tokio::sync::Mutex<tokio::signal::unix::Signal>,
+    #[cfg(feature = "bluetooth")]
+    devices: crate::bluetooth::SharedDeviceSettings,
+}
+
+impl Reloader {
+    #[tracing::instrument(name = "reload", skip(self), err(Display))]
+    fn reload(&self) -> Result<(), config::ConfigError> {
+        let source = config::Source::load(self.path.as_deref())?;
+        #[cfg(feature = "bluetooth")]
+        self.devices
+            .replace(crate::bluetooth::DeviceSettings::load(&source)?);
+        tracing::info!(message = "configuration reloaded", path = ?source.path());
         Ok(())
     }
+
+    async fn run(&self, token: CancellationToken) {
+        let mut hangup = self.hangup.lock().await;
+        loop {
+            tokio::select! {
+                _ = hangup.recv() => {
+                    // the current settings are kept when the new ones are invalid
+                    let _ = self.reload();
+                }
+                _ = token.cancelled() => break,
+            }
+        }
+    }
+}
+
+/// Owns the background tasks and restarts the failed ones until the application stops
+#[derive(Clone, Debug)]
+pub(crate) struct Supervisor {
+    cancel_token: CancellationToken,
+    health: crate::health::Health,
+    tracker: TaskTracker,
+    restarts: opentelemetry::metrics::Counter<u64>,
+}
+
+impl Supervisor {
+    fn new(cancel_token: CancellationToken, health: &crate::health::Health) -> Self {
+        let meter = opentelemetry::global::meter("supervisor");
+        Self {
+            cancel_token,
+            health: health.clone(),
+            tracker: TaskTracker::new(),
+            restarts: crate::metrics::TASK_RESTARTS.u64_counter(&meter),
+        }
+    }
+
+    /// Runs a task until it succeeds or the application stops.
+    ///
+    /// The task is created again when it fails or panics, with a token cancelled when the
+    /// application stops.
+    pub(crate) fn spawn<F, Fut>(&self, name: &'static str, mut task: F)
+    where
+        F: FnMut(CancellationToken) -> Fut + Send + 'static,
+        Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        let this = self.clone();
+        self.tracker.spawn(async move {
+            let mut backoff = RESTART_MIN_BACKOFF;
+            // the task is only marked dead once it stops for good, not during the backoff
+            let guard = this.health.task(name);
+            loop {
+                let started = Instant::now();
+                let outcome = tokio::spawn(task(this.cancel_token.child_token())).await;
+                match outcome {
+                    Ok(Ok(())) => return,
+                    Ok(Err(err)) => tracing::error!(
+                        message = "task failed",
+                        task = name,
+                        error.message = format!("{err:#}"),
+                    ),
+                    Err(err) => tracing::error!(
+                        message = "task panicked",
+                        task = name,
+                        error.message = err.to_string(),
+                    ),
+                }
+                guard.restarting();
+                // a task that ran for a while is restarted quickly again
+                if started.elapsed() > RESTART_MAX_BACKOFF {
+                    backoff = RESTART_MIN_BACKOFF;
+                }
+                tokio::select! {
+                    _ = tokio::time::sleep(backoff) => {}
+                    _ = this.cancel_token.cancelled() => return,
+                }
+                backoff = (backoff * 2).min(RESTART_MAX_BACKOFF);
+                this.restarts.add(1, &[KeyValue::new("task", name)]);
+                tracing::info!(message = "restarting task", task = name);
+                guard.running();
+            }
+        });
+    }
+
+    /// Waits for the tasks to stop, once the application is cancelled
+    async fn shutdown(&self, timeout: Duration) {
+        self.tracker.close();
+        if tokio::time::timeout(timeout, self.tracker.wait())
+            .await
+            .is_err()
+        {
+            tracing::warn!(
+                message = "background tasks still running",
+                tasks = self.tracker.len(),
+            );
+        }
+    }
 }
 
 async fn shutdown_signal(token: CancellationToken) {
@@ -72,9 +331,12 @@ async fn shutdown_signal(token: CancellationToken) {
     tokio::select! {
         _ = ctrl_c => {},
         _ = terminate => {},
+        // the application stopped on its own
+        _ = token.cancelled() => return,
     }
 
     tracing::info!("shutdown requested");
+    crate::systemd::stopping();
 
     token.cancel();
 }
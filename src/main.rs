@@ -1,8 +1,121 @@
-use myhomelab::{ApplicationConfig, Configurable};
+use std::{path::PathBuf, process::ExitCode};
+
+use clap::{Parser, Subcommand};
+use myhomelab::{
+    ApplicationConfig, Configurable,
+    config::{ConfigError, Source},
+};
+
+/// Exit code of configuration errors, as defined in sysexits.h
+const EXIT_CONFIG: u8 = 78;
+
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Configuration file, the default one is only read when it exists
+    #[arg(long, global = true, env = "MYHOMELAB_CONFIG", value_name = "PATH")]
+    config: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Collects the measurements until stopped, used when no command is given
+    Run,
+    /// Validates the configuration and prints it once resolved
+    CheckConfig,
+    /// Lists the nearby bluetooth devices and the driver supporting them
+    Scan {
+        /// Duration of the discovery, in seconds
+        #[arg(long, default_value_t = 10)]
+        duration: u64,
+    },
+    /// Decodes a hexadecimal payload like the given driver would
+    Decode { driver: String, payload: String },
+    /// Prints the version and the enabled features
+    Version,
+}
+
+enum Failure {
+    Config(ConfigError),
+    Other(anyhow::Error),
+}
+
+impl From<anyhow::Error> for Failure {
+    fn from(value: anyhow::Error) -> Self {
+        Self::Other(value)
+    }
+}
+
+fn load_config(cli: &Cli) -> Result<(Source, ApplicationConfig), Failure> {
+    let source = Source::load(cli.config.as_deref()).map_err(Failure::Config)?;
+    let config = ApplicationConfig::load(&source).map_err(Failure::Config)?;
+    Ok((source, config))
+}
+
+async fn execute(mut cli: Cli) -> Result<(), Failure> {
+    match cli.command.take() {
+        None | Some(Command::Run) => {
+            let (_, config) = load_config(&cli)?;
+            let app = config.build().await?;
+            app.run().await?;
+        }
+        Some(Command::CheckConfig) => {
+            let (source, config) = load_config(&cli)?;
+            match source.path() {
+                Some(path) => println!("configuration file: {}", path.display()),
+                None => println!("configuration file: none, environment only"),
+            }
+            println!("{config:#?}");
+        }
+        #[cfg(feature = "bluetooth")]
+        Some(Command::Scan { duration }) => {
+            let devices = myhomelab::scan(std::time::Duration::from_secs(duration)).await?;
+            for device in devices {
+                println!(
+                    "{}\t{}\t{}\t{}",
+                    device.address,
+                    device
+                        .rssi
+                        .map_or_else(|| String::from("-"), |rssi| format!("{rssi} dBm")),
+                    device.driver.unwrap_or("-"),
+                    device.name.as_deref().unwrap_or("-"),
+                );
+            }
+        }
+        #[cfg(feature = "bluetooth")]
+        Some(Command::Decode { driver, payload }) => {
+            for (name, value) in myhomelab::decode(&driver, &payload)? {
+                println!("{name}: {value}");
+            }
+        }
+        #[cfg(not(feature = "bluetooth"))]
+        Some(Command::Scan { .. } | Command::Decode { .. }) => {
+            return Err(Failure::Other(anyhow::anyhow!(
+                "built without the bluetooth feature"
+            )));
+        }
+        Some(Command::Version) => {
+            println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+            println!("bluetooth: {}", cfg!(feature = "bluetooth"));
+            println!("storage: {}", cfg!(feature = "storage"));
+        }
+    }
+    Ok(())
+}
 
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    let config = ApplicationConfig::from_env()?;
-    let app = config.build().await?;
-    app.run().await
+async fn main() -> ExitCode {
+    match execute(Cli::parse()).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(Failure::Config(err)) => {
+            eprintln!("configuration error: {err}");
+            ExitCode::from(EXIT_CONFIG)
+        }
+        Err(Failure::Other(err)) => {
+            eprintln!("error: {err:#}");
+            ExitCode::FAILURE
+        }
+    }
 }
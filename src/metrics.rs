@@ -0,0 +1,481 @@
+//! Catalogue of the metrics recorded by the collectors.
+//!
+//! Every instrument is defined here and built from its definition. Units follow UCUM, like
+//! the OpenTelemetry semantic conventions recommend. The names used before the catalogue
+//! existed can still be emitted with their previous units during the migration of the
+//! dashboards.
+
+// only the collectors use the catalogue
+#![cfg_attr(not(feature = "bluetooth"), allow(dead_code))]
+
+use std::sync::OnceLock;
+
+use opentelemetry::{
+    KeyValue,
+    metrics::{AsyncInstrument, Counter, Gauge as MetricGauge, Meter},
+};
+
+static LEGACY_NAMES: OnceLock<bool> = OnceLock::new();
+
+#[derive(Debug, Default)]
+pub struct MetricsConfig {
+    /// Also emits the metrics under their previous names
+    pub legacy_names: bool,
+}
+
+impl crate::Configurable for MetricsConfig {
+    fn from_source(source: &crate::config::Source) -> Self {
+        Self {
+            legacy_names: source
+                .parse("METRICS_LEGACY_NAMES", "metrics.legacy_names")
+                .unwrap_or_default(),
+        }
+    }
+}
+
+impl MetricsConfig {
+    /// Must be called before building the collectors
+    pub(crate) fn install(&self) {
+        let _ = LEGACY_NAMES.set(self.legacy_names);
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Definition {
+    pub name: &'static str,
+    pub unit: &'static str,
+    pub description: &'static str,
+    /// Instrument emitted before the catalogue
+    pub legacy: Option<Legacy>,
+    /// Kept by the storage, only for the readings of the sensors as the other gauges are
+    /// recorded for every device around
+    pub stored: bool,
+}
+
+#[derive(Debug)]
+pub(crate) struct Legacy {
+    pub name: &'static str,
+    /// Free text unit, empty when there was none
+    pub unit: &'static str,
+}
+
+// sensors
+
+pub(crate) const TEMPERATURE: Definition = Definition {
+    name: "sensor.temperature",
+    unit: "Cel",
+    description: "Ambient temperature",
+    legacy: Some(Legacy {
+        name: "measurement.temperature",
+        unit: "degree celcius",
+    }),
+    stored: true,
+};
+
+pub(crate) const HUMIDITY: Definition = Definition {
+    name: "sensor.humidity",
+    unit: "%",
+    description: "Relative humidity of the air",
+    legacy: Some(Legacy {
+        name: "measurement.humidity",
+        unit: "percentage",
+    }),
+    stored: true,
+};
+
+pub(crate) const ILLUMINANCE: Definition = Definition {
+    name: "sensor.illuminance",
+    unit: "lx",
+    description: "Light received by the sensor",
+    legacy: Some(Legacy {
+        name: "measurement.brightness",
+        unit: "lux",
+    }),
+    stored: true,
+};
+
+pub(crate) const SOIL_MOISTURE: Definition = Definition {
+    name: "sensor.soil.moisture",
+    unit: "%",
+    description: "Water content of the soil",
+    legacy: Some(Legacy {
+        name: "measurement.moisture",
+        unit: "percent",
+    }),
+    stored: true,
+};
+
+pub(crate) const SOIL_CONDUCTIVITY: Definition = Definition {
+    name: "sensor.soil.conductivity",
+    unit: "uS/cm",
+    description: "Electrical conductivity of the soil, related to its fertility",
+    legacy: Some(Legacy {
+        name: "measurement.conductivity",
+        unit: "",
+    }),
+    stored: true,
+};
+
+pub(crate) const BATTERY_LEVEL: Definition = Definition {
+    name: "sensor.battery.level",
+    unit: "%",
+    description: "Remaining battery of the sensor",
+    legacy: Some(Legacy {
+        name: "system.battery",
+        unit: "percent",
+    }),
+    stored: true,
+};
+
+/// Battery level of the LYWSD03MMC, whose legacy unit was spelled differently
+pub(crate) const BATTERY_LEVEL_PERCENTAGE: Definition = Definition {
+    legacy: Some(Legacy {
+        name: "system.battery",
+        unit: "percentage",
+    }),
+    ..BATTERY_LEVEL
+};
+
+// bluetooth
+
+pub(crate) const EVENTS: Definition = Definition {
+    name: "bluetooth.events",
+    unit: "{event}",
+    description: "Number of adapter events received, by kind",
+    legacy: None,
+    stored: false,
+};
+
+pub(crate) const DEVICES: Definition = Definition {
+    name: "bluetooth.devices",
+    unit: "{device}",
+    description: "Number of discovered devices",
+    legacy: None,
+    stored: false,
+};
+
+pub(crate) const DEVICE_RSSI: Definition = Definition {
+    name: "bluetooth.device.rssi",
+    unit: "dBm",
+    description: "Received Signal Strength Indicator",
+    legacy: None,
+    stored: false,
+};
+
+pub(crate) const DEVICE_RSSI_SMOOTHED: Definition = Definition {
+    name: "bluetooth.device.rssi.smoothed",
+    unit: "dBm",
+    description: "Smoothed Received Signal Strength Indicator",
+    legacy: None,
+    stored: false,
+};
+
+pub(crate) const DEVICE_RSSI_VARIANCE: Definition = Definition {
+    name: "bluetooth.device.rssi.variance",
+    unit: "dBm2",
+    description: "Variance of the Received Signal Strength Indicator",
+    legacy: None,
+    stored: false,
+};
+
+pub(crate) const DEVICE_DISTANCE: Definition = Definition {
+    name: "bluetooth.device.distance",
+    unit: "m",
+    description: "Distance to the adapter estimated from the smoothed RSSI",
+    legacy: None,
+    stored: false,
+};
+
+pub(crate) const QUEUE_EVENTS_SENT: Definition = Definition {
+    name: "queue.events.sent",
+    unit: "{event}",
+    description: "Number of events sent in the queue",
+    legacy: None,
+    stored: false,
+};
+
+pub(crate) const QUEUE_EVENTS_SENT_ERROR: Definition = Definition {
+    name: "queue.events.sent.error",
+    unit: "{event}",
+    description: "Number of events that failed being sent in the queue",
+    legacy: None,
+    stored: false,
+};
+
+pub(crate) const QUEUE_EVENTS_RECEIVED: Definition = Definition {
+    name: "queue.events.received",
+    unit: "{event}",
+    description: "Number of events received from the queue",
+    legacy: None,
+    stored: false,
+};
+
+// beacons
+
+pub(crate) const BEACON_FRAMES: Definition = Definition {
+    name: "beacon.frames",
+    unit: "{frame}",
+    description: "Number of beacon frames received",
+    legacy: None,
+    stored: false,
+};
+
+pub(crate) const BEACON_DISTANCE: Definition = Definition {
+    name: "beacon.distance",
+    unit: "m",
+    description: "Distance estimated from the calibrated power and the RSSI",
+    legacy: None,
+    stored: false,
+};
+
+pub(crate) const BEACON_BATTERY_VOLTAGE: Definition = Definition {
+    name: "beacon.battery.voltage",
+    unit: "V",
+    description: "Battery voltage advertised in Eddystone telemetry frames",
+    legacy: None,
+    stored: true,
+};
+
+pub(crate) const BEACON_TEMPERATURE: Definition = Definition {
+    name: "beacon.temperature",
+    unit: "Cel",
+    description: "Temperature advertised in Eddystone telemetry frames",
+    legacy: None,
+    stored: true,
+};
+
+pub(crate) const BEACON_ADVERTISING_COUNT: Definition = Definition {
+    name: "beacon.advertising.count",
+    unit: "{advertisement}",
+    description: "Number of advertisements sent since boot",
+    legacy: None,
+    stored: false,
+};
+
+pub(crate) const BEACON_UPTIME: Definition = Definition {
+    name: "beacon.uptime",
+    unit: "s",
+    description: "Time since the beacon booted",
+    legacy: None,
+    stored: false,
+};
+
+// presence
+
+pub(crate) const PRESENCE_STATE: Definition = Definition {
+    name: "presence.state",
+    unit: "1",
+    description: "Whether the device is considered present (1) or away (0)",
+    legacy: None,
+    stored: false,
+};
+
+pub(crate) const PRESENCE_LAST_SEEN: Definition = Definition {
+    name: "presence.last_seen",
+    unit: "s",
+    description: "Timestamp of the last time the device has been seen",
+    legacy: None,
+    stored: false,
+};
+
+// application
+
+pub(crate) const TASK_RESTARTS: Definition = Definition {
+    name: "task.restarts",
+    unit: "{restart}",
+    description: "Number of background tasks restarted after failing",
+    legacy: None,
+    stored: false,
+};
+
+#[cfg(feature = "storage")]
+pub(crate) const STORAGE_MEASUREMENTS_DROPPED: Definition = Definition {
+    name: "storage.measurements.dropped",
+    unit: "{measurement}",
+    description: "Number of measurements not stored as the queue was full",
+    legacy: None,
+    stored: false,
+};
+
+pub(crate) const TELEMETRY_BUFFER_POINTS: Definition = Definition {
+    name: "telemetry.buffer.points",
+    unit: "{point}",
+    description: "Number of metric points waiting for the collector",
+    legacy: None,
+    stored: false,
+};
+
+pub(crate) const TELEMETRY_BUFFER_POINTS_DROPPED: Definition = Definition {
+    name: "telemetry.buffer.points.dropped",
+    unit: "{point}",
+    description: "Number of metric points dropped as the buffer was full",
+    legacy: None,
+    stored: false,
+};
+
+impl Definition {
+    pub(crate) fn f64_gauge(&self, meter: &Meter) -> Gauge<f64> {
+        self.gauge(meter)
+    }
+
+    pub(crate) fn i64_gauge(&self, meter: &Meter) -> Gauge<i64> {
+        self.gauge(meter)
+    }
+
+    pub(crate) fn u64_gauge(&self, meter: &Meter) -> Gauge<u64> {
+        self.gauge(meter)
+    }
+
+    /// Gauge emitted under the legacy name too, when enabled
+    fn gauge<T: Value>(&self, meter: &Meter) -> Gauge<T> {
+        let legacy = LEGACY_NAMES
+            .get()
+            .copied()
+            .unwrap_or_default()
+            .then_some(self.legacy.as_ref())
+            .flatten();
+        Gauge {
+            name: self.name,
+            unit: self.unit,
+            stored: self.stored,
+            current: T::gauge(meter, self.name, self.description, Some(self.unit)),
+            legacy: legacy.map(|legacy| {
+                let unit = Some(legacy.unit).filter(|unit| !unit.is_empty());
+                T::gauge(meter, legacy.name, self.description, unit)
+            }),
+        }
+    }
+
+    pub(crate) fn u64_counter(&self, meter: &Meter) -> Counter<u64> {
+        meter
+            .u64_counter(self.name)
+            .with_description(self.description)
+            .with_unit(self.unit)
+            .build()
+    }
+
+    /// Gauge observed by the callback on every collection
+    pub(crate) fn u64_observable_gauge<F>(&self, meter: &Meter, callback: F)
+    where
+        F: Fn(&dyn AsyncInstrument<u64>) + Send + Sync + 'static,
+    {
+        meter
+            .u64_observable_gauge(self.name)
+            .with_description(self.description)
+            .with_unit(self.unit)
+            .with_callback(callback)
+            .build();
+    }
+
+    /// Counter observed by the callback on every collection
+    pub(crate) fn u64_observable_counter<F>(&self, meter: &Meter, callback: F)
+    where
+        F: Fn(&dyn AsyncInstrument<u64>) + Send + Sync + 'static,
+    {
+        meter
+            .u64_observable_counter(self.name)
+            .with_description(self.description)
+            .with_unit(self.unit)
+            .with_callback(callback)
+            .build();
+    }
+}
+
+/// Types of the values recorded by the gauges of the catalogue
+pub(crate) trait Value: Copy {
+    fn gauge(
+        meter: &Meter,
+        name: &'static str,
+        description: &'static str,
+        unit: Option<&'static str>,
+    ) -> MetricGauge<Self>;
+
+    /// Stored value, the measurements are kept as reals
+    #[cfg_attr(not(feature = "storage"), allow(dead_code))]
+    fn as_f64(self) -> f64;
+}
+
+impl Value for f64 {
+    fn gauge(
+        meter: &Meter,
+        name: &'static str,
+        description: &'static str,
+        unit: Option<&'static str>,
+    ) -> MetricGauge<Self> {
+        let builder = meter.f64_gauge(name).with_description(description);
+        match unit {
+            Some(unit) => builder.with_unit(unit).build(),
+            None => builder.build(),
+        }
+    }
+
+    fn as_f64(self) -> f64 {
+        self
+    }
+}
+
+impl Value for i64 {
+    fn gauge(
+        meter: &Meter,
+        name: &'static str,
+        description: &'static str,
+        unit: Option<&'static str>,
+    ) -> MetricGauge<Self> {
+        let builder = meter.i64_gauge(name).with_description(description);
+        match unit {
+            Some(unit) => builder.with_unit(unit).build(),
+            None => builder.build(),
+        }
+    }
+
+    fn as_f64(self) -> f64 {
+        self as f64
+    }
+}
+
+impl Value for u64 {
+    fn gauge(
+        meter: &Meter,
+        name: &'static str,
+        description: &'static str,
+        unit: Option<&'static str>,
+    ) -> MetricGauge<Self> {
+        let builder = meter.u64_gauge(name).with_description(description);
+        match unit {
+            Some(unit) => builder.with_unit(unit).build(),
+            None => builder.build(),
+        }
+    }
+
+    fn as_f64(self) -> f64 {
+        self as f64
+    }
+}
+
+/// Gauge recording under the catalogue name and, when enabled, the legacy one.
+///
+/// The readings of the sensors are also kept by the storage, when enabled.
+#[derive(Clone, Debug)]
+pub(crate) struct Gauge<T> {
+    #[cfg_attr(not(feature = "storage"), allow(dead_code))]
+    name: &'static str,
+    #[cfg_attr(not(feature = "storage"), allow(dead_code))]
+    unit: &'static str,
+    #[cfg_attr(not(feature = "storage"), allow(dead_code))]
+    stored: bool,
+    current: MetricGauge<T>,
+    legacy: Option<MetricGauge<T>>,
+}
+
+impl<T: Value> Gauge<T> {
+    pub(crate) fn record(&self, value: T, attributes: &[KeyValue]) {
+        self.current.record(value, attributes);
+        if let Some(ref legacy) = self.legacy {
+            legacy.record(value, attributes);
+        }
+        #[cfg(feature = "storage")]
+        if self.stored {
+            crate::storage::record(self.name, self.unit, value.as_f64(), attributes);
+        }
+    }
+}
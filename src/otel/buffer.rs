@@ -0,0 +1,593 @@
+//! Metric exports that failed, kept on disk and sent again once the collector is back.
+//!
+//! Every failed batch is written as an OTLP protobuf request in its own file, named after its
+//! position in the queue. The queue is replayed in order before any new batch is sent, so the
+//! collector receives the points in the order they were recorded. The batches the collector
+//! refuses are dropped so that they don't hold the queue back.
+
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::Context;
+use opentelemetry::metrics::Meter;
+use opentelemetry_proto::tonic::{
+    collector::metrics::v1::{
+        ExportMetricsServiceRequest, metrics_service_client::MetricsServiceClient,
+    },
+    metrics::v1::metric::Data,
+};
+use opentelemetry_sdk::{
+    error::{OTelSdkError, OTelSdkResult},
+    metrics::{Temporality, data::ResourceMetrics, exporter::PushMetricExporter},
+};
+use prost::Message;
+
+use super::transport::{OtlpProtocol, OtlpSignal, Transport};
+use crate::{config::Source, metrics};
+
+/// Buffered batches sent at most on each export, the new batches are queued until the
+/// backlog is gone
+const REPLAY_BATCHES: usize = 10;
+
+/// Buffering of the metrics in `otlp` mode
+#[derive(Debug)]
+pub struct MetricBufferConfig {
+    pub enabled: bool,
+    /// Defaults to the state directory given by systemd
+    pub directory: Option<PathBuf>,
+    /// Size in bytes after which the oldest batches are dropped
+    pub max_size: u64,
+}
+
+impl crate::Configurable for MetricBufferConfig {
+    fn from_source(source: &Source) -> Self {
+        Self {
+            enabled: source
+                .parse("TELEMETRY_BUFFER_ENABLED", "telemetry.buffer.enabled")
+                .unwrap_or_default(),
+            directory: source.parse("TELEMETRY_BUFFER_DIRECTORY", "telemetry.buffer.directory"),
+            max_size: source
+                .parse("TELEMETRY_BUFFER_MAX_SIZE", "telemetry.buffer.max_size")
+                .unwrap_or(50 * 1024 * 1024),
+        }
+    }
+}
+
+impl MetricBufferConfig {
+    pub(super) fn open(&self) -> anyhow::Result<Option<Arc<Buffer>>> {
+        if !self.enabled {
+            return Ok(None);
+        }
+        let directory = self
+            .directory
+            .clone()
+            .unwrap_or_else(|| crate::config::state_directory().join("metrics-buffer"));
+        Buffer::open(directory, self.max_size).map(|buffer| Some(Arc::new(buffer)))
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    path: PathBuf,
+    size: u64,
+    points: u64,
+}
+
+#[derive(Debug, Default)]
+struct Queue {
+    entries: VecDeque<Entry>,
+    size: u64,
+    next: u64,
+}
+
+/// Failed batches in the order they were exported
+#[derive(Debug)]
+pub(super) struct Buffer {
+    directory: PathBuf,
+    max_size: u64,
+    queue: Mutex<Queue>,
+    buffered: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl Buffer {
+    /// Loads the batches left by the previous run
+    fn open(directory: PathBuf, max_size: u64) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(&directory)
+            .with_context(|| format!("unable to create {directory:?}"))?;
+        let mut files = Vec::new();
+        for item in std::fs::read_dir(&directory)
+            .with_context(|| format!("unable to read {directory:?}"))?
+        {
+            let path = item?.path();
+            let index = path
+                .extension()
+                .filter(|extension| *extension == "pb")
+                .and_then(|_| path.file_stem()?.to_str()?.parse::<u64>().ok());
+            if let Some(index) = index {
+                files.push((index, path));
+            }
+        }
+        files.sort();
+
+        let mut queue = Queue {
+            next: files.last().map_or(0, |(index, _)| index + 1),
+            ..Default::default()
+        };
+        for (_, path) in files {
+            match read(&path) {
+                Ok((request, size)) => {
+                    queue.size += size;
+                    queue.entries.push_back(Entry {
+                        path,
+                        size,
+                        points: points(&request),
+                    });
+                }
+                Err(err) => {
+                    tracing::warn!(
+                        message = "dropping unreadable buffered metrics",
+                        path = ?path,
+                        error.message = format!("{err:#}"),
+                    );
+                    let _ = std::fs::remove_file(&path);
+                }
+            }
+        }
+        let buffered = queue.entries.iter().map(|entry| entry.points).sum();
+        if buffered > 0 {
+            tracing::info!(
+                message = "metrics buffered by the previous run",
+                points = buffered,
+                batches = queue.entries.len(),
+            );
+        }
+        Ok(Self {
+            directory,
+            max_size,
+            queue: Mutex::new(queue),
+            buffered: AtomicU64::new(buffered),
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    /// Exposes the number of buffered and dropped points
+    pub(super) fn register(self: &Arc<Self>, meter: &Meter) {
+        let buffer = self.clone();
+        metrics::TELEMETRY_BUFFER_POINTS.u64_observable_gauge(meter, move |observer| {
+            observer.observe(buffer.buffered.load(Ordering::Relaxed), &[])
+        });
+        let buffer = self.clone();
+        metrics::TELEMETRY_BUFFER_POINTS_DROPPED.u64_observable_counter(meter, move |observer| {
+            observer.observe(buffer.dropped.load(Ordering::Relaxed), &[])
+        });
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queue
+            .lock()
+            .expect("buffer lock poisoned")
+            .entries
+            .is_empty()
+    }
+
+    /// Adds a batch at the end of the queue, dropping the oldest ones to stay under the size
+    fn push(&self, request: &ExportMetricsServiceRequest) {
+        let content = request.encode_to_vec();
+        let size = content.len() as u64;
+        let points = points(request);
+        if size > self.max_size {
+            self.drop_points(points);
+            return;
+        }
+        let mut queue = self.queue.lock().expect("buffer lock poisoned");
+        while queue.size + size > self.max_size {
+            let Some(entry) = queue.entries.pop_front() else {
+                break;
+            };
+            queue.size -= entry.size;
+            let _ = std::fs::remove_file(&entry.path);
+            self.buffered.fetch_sub(entry.points, Ordering::Relaxed);
+            self.drop_points(entry.points);
+        }
+        let path = self.directory.join(format!("{:020}.pb", queue.next));
+        if let Err(err) = write_atomically(&path, &content) {
+            tracing::warn!(
+                message = "unable to buffer metrics",
+                path = ?path,
+                error.message = err.to_string(),
+            );
+            self.drop_points(points);
+            return;
+        }
+        queue.next += 1;
+        queue.size += size;
+        queue.entries.push_back(Entry { path, size, points });
+        self.buffered.fetch_add(points, Ordering::Relaxed);
+    }
+
+    fn drop_points(&self, points: u64) {
+        self.dropped.fetch_add(points, Ordering::Relaxed);
+        tracing::warn!(
+            message = "metric buffer full, dropping points",
+            points = points
+        );
+    }
+
+    fn front(&self) -> Option<PathBuf> {
+        let queue = self.queue.lock().expect("buffer lock poisoned");
+        queue.entries.front().map(|entry| entry.path.clone())
+    }
+
+    fn pop(&self) {
+        let mut queue = self.queue.lock().expect("buffer lock poisoned");
+        if let Some(entry) = queue.entries.pop_front() {
+            queue.size -= entry.size;
+            let _ = std::fs::remove_file(&entry.path);
+            self.buffered.fetch_sub(entry.points, Ordering::Relaxed);
+        }
+    }
+}
+
+fn read(path: &Path) -> anyhow::Result<(ExportMetricsServiceRequest, u64)> {
+    let content = std::fs::read(path)?;
+    let request = ExportMetricsServiceRequest::decode(content.as_slice())?;
+    Ok((request, content.len() as u64))
+}
+
+fn write_atomically(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, content)?;
+    std::fs::rename(tmp, path)
+}
+
+fn points(request: &ExportMetricsServiceRequest) -> u64 {
+    request
+        .resource_metrics
+        .iter()
+        .flat_map(|resource| &resource.scope_metrics)
+        .flat_map(|scope| &scope.metrics)
+        .map(|metric| match metric.data {
+            Some(Data::Gauge(ref data)) => data.data_points.len(),
+            Some(Data::Sum(ref data)) => data.data_points.len(),
+            Some(Data::Histogram(ref data)) => data.data_points.len(),
+            Some(Data::ExponentialHistogram(ref data)) => data.data_points.len(),
+            Some(Data::Summary(ref data)) => data.data_points.len(),
+            None => 0,
+        } as u64)
+        .sum()
+}
+
+/// Failure of a buffered request sent again
+#[derive(Debug)]
+enum ForwardError {
+    /// Refused by the collector, sending it again won't help
+    Rejected(String),
+    /// Transport failure or temporary refusal, sent again on the next export
+    Retryable(OTelSdkError),
+}
+
+/// Client errors are permanent, except the timeouts and the rate limits
+fn is_rejection(status: reqwest::StatusCode) -> bool {
+    status.is_client_error()
+        && status != reqwest::StatusCode::REQUEST_TIMEOUT
+        && status != reqwest::StatusCode::TOO_MANY_REQUESTS
+}
+
+/// Sends the buffered requests as they were stored, without going through the exporter
+#[derive(Debug)]
+enum Forwarder {
+    Grpc {
+        client: MetricsServiceClient<tonic::transport::Channel>,
+        metadata: tonic::metadata::MetadataMap,
+        timeout: Duration,
+    },
+    Http {
+        client: reqwest::blocking::Client,
+        endpoint: String,
+        headers: Vec<(String, String)>,
+        json: bool,
+        timeout: Duration,
+    },
+}
+
+impl Forwarder {
+    /// Must be called from the runtime, which drives the gRPC connection
+    fn new(transport: &Transport, signal: &OtlpSignal, timeout: Duration) -> anyhow::Result<Self> {
+        Ok(match signal.protocol {
+            OtlpProtocol::Grpc => {
+                let mut endpoint = tonic::transport::Channel::from_shared(signal.endpoint.clone())
+                    .with_context(|| format!("invalid endpoint {:?}", signal.endpoint))?
+                    .timeout(timeout);
+                if signal.endpoint.starts_with("https://") {
+                    endpoint = endpoint.tls_config(transport.tonic_tls())?;
+                }
+                Self::Grpc {
+                    client: MetricsServiceClient::new(endpoint.connect_lazy()),
+                    metadata: transport.metadata()?,
+                    timeout,
+                }
+            }
+            OtlpProtocol::HttpProtobuf | OtlpProtocol::HttpJson => Self::Http {
+                client: transport.http_client()?,
+                endpoint: signal.endpoint.clone(),
+                headers: transport.headers().0.clone(),
+                json: signal.protocol == OtlpProtocol::HttpJson,
+                timeout,
+            },
+        })
+    }
+
+    async fn send(&self, request: ExportMetricsServiceRequest) -> Result<(), ForwardError> {
+        match self {
+            Self::Grpc {
+                client,
+                metadata,
+                timeout,
+            } => {
+                let mut request = tonic::Request::new(request);
+                *request.metadata_mut() = metadata.clone();
+                request.set_timeout(*timeout);
+                client
+                    .clone()
+                    .export(request)
+                    .await
+                    .map(|_| ())
+                    .map_err(|status| match status.code() {
+                        tonic::Code::InvalidArgument => ForwardError::Rejected(status.to_string()),
+                        _ => ForwardError::Retryable(OTelSdkError::InternalFailure(
+                            status.to_string(),
+                        )),
+                    })
+            }
+            Self::Http {
+                client,
+                endpoint,
+                headers,
+                json,
+                timeout,
+            } => {
+                let (content_type, body) = if *json {
+                    let body = serde_json::to_vec(&request)
+                        .map_err(|err| ForwardError::Rejected(err.to_string()))?;
+                    ("application/json", body)
+                } else {
+                    ("application/x-protobuf", request.encode_to_vec())
+                };
+                let mut builder = client
+                    .post(endpoint)
+                    .timeout(*timeout)
+                    .header(reqwest::header::CONTENT_TYPE, content_type)
+                    .body(body);
+                for (key, value) in headers {
+                    builder = builder.header(key, value);
+                }
+                builder
+                    .send()
+                    .and_then(|response| response.error_for_status())
+                    .map(|_| ())
+                    .map_err(|err| match err.status() {
+                        Some(status) if is_rejection(status) => {
+                            ForwardError::Rejected(err.to_string())
+                        }
+                        _ => {
+                            ForwardError::Retryable(OTelSdkError::InternalFailure(err.to_string()))
+                        }
+                    })
+            }
+        }
+    }
+}
+
+/// Buffers the batches the inner exporter fails to send and replays them before the new ones
+#[derive(Debug)]
+pub(super) struct BufferedMetricExporter<E> {
+    inner: E,
+    buffer: Arc<Buffer>,
+    forwarder: Forwarder,
+}
+
+impl<E> BufferedMetricExporter<E> {
+    pub(super) fn new(
+        inner: E,
+        buffer: Arc<Buffer>,
+        transport: &Transport,
+        signal: &OtlpSignal,
+        timeout: Duration,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            inner,
+            buffer,
+            forwarder: Forwarder::new(transport, signal, timeout)?,
+        })
+    }
+
+    /// Sends the oldest batches, returns whether the backlog is gone.
+    ///
+    /// The batches rejected by the collector are dropped, the replay only stops on the errors
+    /// that can be retried.
+    async fn replay(&self) -> Result<bool, OTelSdkError> {
+        for _ in 0..REPLAY_BATCHES {
+            let Some(path) = self.buffer.front() else {
+                return Ok(true);
+            };
+            match read(&path) {
+                Ok((request, _)) => {
+                    let points = points(&request);
+                    match self.forwarder.send(request).await {
+                        Ok(()) => {}
+                        Err(ForwardError::Rejected(message)) => {
+                            self.buffer.dropped.fetch_add(points, Ordering::Relaxed);
+                            tracing::warn!(
+                                message = "collector rejected buffered metrics, dropping points",
+                                points = points,
+                                error.message = message,
+                            );
+                        }
+                        Err(ForwardError::Retryable(err)) => return Err(err),
+                    }
+                }
+                Err(err) => tracing::warn!(
+                    message = "dropping unreadable buffered metrics",
+                    path = ?path,
+                    error.message = format!("{err:#}"),
+                ),
+            }
+            self.buffer.pop();
+        }
+        Ok(self.buffer.is_empty())
+    }
+}
+
+impl<E: PushMetricExporter> PushMetricExporter for BufferedMetricExporter<E> {
+    async fn export(&self, metrics: &ResourceMetrics) -> OTelSdkResult {
+        match self.replay().await {
+            Ok(true) => {}
+            // queued behind the backlog to keep the order, it failed until the backlog is gone
+            Ok(false) => {
+                self.buffer
+                    .push(&ExportMetricsServiceRequest::from(metrics));
+                return Err(OTelSdkError::InternalFailure(
+                    "buffered behind the metrics still to replay".into(),
+                ));
+            }
+            Err(err) => {
+                self.buffer
+                    .push(&ExportMetricsServiceRequest::from(metrics));
+                return Err(err);
+            }
+        }
+        let result = self.inner.export(metrics).await;
+        if result.is_err() {
+            self.buffer
+                .push(&ExportMetricsServiceRequest::from(metrics));
+        }
+        result
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.inner.force_flush()
+    }
+
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        self.inner.shutdown_with_timeout(timeout)
+    }
+
+    fn temporality(&self) -> Temporality {
+        self.inner.temporality()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry_proto::tonic::metrics::v1::{
+        Gauge, Metric, NumberDataPoint, ResourceMetrics, ScopeMetrics,
+    };
+
+    use super::*;
+
+    /// Empty directory, unique to the test
+    fn directory(name: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("myhomelab-buffer-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        directory
+    }
+
+    /// Batch named after the metric it holds, all the names have the same size
+    fn request(name: &str, points: usize) -> ExportMetricsServiceRequest {
+        ExportMetricsServiceRequest {
+            resource_metrics: vec![ResourceMetrics {
+                scope_metrics: vec![ScopeMetrics {
+                    metrics: vec![Metric {
+                        name: name.to_string(),
+                        data: Some(Data::Gauge(Gauge {
+                            data_points: vec![NumberDataPoint::default(); points],
+                        })),
+                        ..Default::default()
+                    }],
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+        }
+    }
+
+    /// Empties the buffer like the replay, returns the names of the batches
+    fn drain(buffer: &Buffer) -> Vec<String> {
+        let mut names = Vec::new();
+        while let Some(path) = buffer.front() {
+            let (request, _) = read(&path).unwrap();
+            names.push(
+                request.resource_metrics[0].scope_metrics[0].metrics[0]
+                    .name
+                    .clone(),
+            );
+            buffer.pop();
+        }
+        names
+    }
+
+    #[test]
+    fn replays_the_batches_in_order_after_a_restart() {
+        let directory = directory("order");
+        let buffer = Buffer::open(directory.clone(), u64::MAX).unwrap();
+        // more than ten batches, so that the order doesn't follow the names of the files
+        let names = (0..12)
+            .map(|index| format!("m{index:02}"))
+            .collect::<Vec<_>>();
+        for name in &names {
+            buffer.push(&request(name, 2));
+        }
+        assert_eq!(buffer.buffered.load(Ordering::Relaxed), 24);
+        drop(buffer);
+
+        let buffer = Buffer::open(directory.clone(), u64::MAX).unwrap();
+        assert_eq!(buffer.buffered.load(Ordering::Relaxed), 24);
+        assert_eq!(drain(&buffer), names);
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.buffered.load(Ordering::Relaxed), 0);
+        assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 0);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn drops_the_oldest_batches_when_full() {
+        let directory = directory("eviction");
+        let size = request("m0", 3).encode_to_vec().len() as u64;
+        let buffer = Buffer::open(directory.clone(), 2 * size).unwrap();
+        buffer.push(&request("m0", 3));
+        buffer.push(&request("m1", 3));
+        buffer.push(&request("m2", 3));
+        assert_eq!(buffer.dropped.load(Ordering::Relaxed), 3);
+        assert_eq!(buffer.buffered.load(Ordering::Relaxed), 6);
+        assert_eq!(drain(&buffer), ["m1", "m2"]);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn only_drops_the_batches_refused_for_good() {
+        assert!(is_rejection(reqwest::StatusCode::BAD_REQUEST));
+        assert!(is_rejection(reqwest::StatusCode::PAYLOAD_TOO_LARGE));
+        assert!(!is_rejection(reqwest::StatusCode::REQUEST_TIMEOUT));
+        assert!(!is_rejection(reqwest::StatusCode::TOO_MANY_REQUESTS));
+        assert!(!is_rejection(reqwest::StatusCode::SERVICE_UNAVAILABLE));
+    }
+
+    #[test]
+    fn drops_the_batches_larger_than_the_buffer() {
+        let directory = directory("oversized");
+        let buffer = Buffer::open(directory.clone(), 16).unwrap();
+        buffer.push(&request("m0", 10));
+        assert_eq!(buffer.dropped.load(Ordering::Relaxed), 10);
+        assert!(buffer.is_empty());
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,268 @@
+//! Exporters writing the telemetry locally, for when no collector is available.
+//!
+//! Every batch is serialized as an OTLP JSON request on a single line, so the output
+//! can later be replayed to a collector or inspected with `jq`.
+
+use std::{
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Context;
+use opentelemetry_proto::{
+    tonic::collector::{
+        logs::v1::ExportLogsServiceRequest, metrics::v1::ExportMetricsServiceRequest,
+        trace::v1::ExportTraceServiceRequest,
+    },
+    transform::{
+        common::tonic::ResourceAttributesWithSchema, logs::tonic::group_logs_by_resource_and_scope,
+        trace::tonic::group_spans_by_resource_and_scope,
+    },
+};
+use opentelemetry_sdk::{
+    Resource,
+    error::{OTelSdkError, OTelSdkResult},
+    logs::{LogBatch, LogExporter},
+    metrics::{Temporality, data::ResourceMetrics, exporter::PushMetricExporter},
+    trace::{SpanData, SpanExporter},
+};
+
+/// Configuration of the rotating files used in `file` mode
+#[derive(Debug)]
+pub struct FileExporterConfig {
+    /// Directory containing one file per signal
+    pub directory: PathBuf,
+    /// Size in bytes after which a file is rotated
+    pub max_size: u64,
+    /// Number of rotated files kept for each signal
+    pub max_files: usize,
+}
+
+impl crate::Configurable for FileExporterConfig {
+    fn from_source(source: &crate::config::Source) -> Self {
+        Self {
+            directory: source
+                .parse("TELEMETRY_FILE_DIRECTORY", "telemetry.file.directory")
+                .unwrap_or_else(|| PathBuf::from("telemetry")),
+            max_size: source
+                .parse("TELEMETRY_FILE_MAX_SIZE", "telemetry.file.max_size")
+                .unwrap_or(10 * 1024 * 1024),
+            max_files: source
+                .parse("TELEMETRY_FILE_MAX_FILES", "telemetry.file.max_files")
+                .unwrap_or(5),
+        }
+    }
+}
+
+impl FileExporterConfig {
+    pub(super) fn output(&self, signal: &str) -> anyhow::Result<Output> {
+        std::fs::create_dir_all(&self.directory)
+            .with_context(|| format!("unable to create {:?}", self.directory))?;
+        let file = RotatingFile {
+            path: self.directory.join(format!("{signal}.jsonl")),
+            max_size: self.max_size,
+            max_files: self.max_files,
+            current: None,
+        };
+        Ok(Output::File(Arc::new(Mutex::new(file))))
+    }
+}
+
+/// JSONL file renamed to `<signal>.1.jsonl`, `<signal>.2.jsonl`... once too big
+#[derive(Debug)]
+pub(super) struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    max_files: usize,
+    current: Option<(File, u64)>,
+}
+
+impl RotatingFile {
+    fn rotated(&self, index: usize) -> PathBuf {
+        self.path.with_extension(format!("{index}.jsonl"))
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        self.current = None;
+        if self.max_files == 0 {
+            return remove_if_exists(&self.path);
+        }
+        remove_if_exists(&self.rotated(self.max_files))?;
+        for index in (1..self.max_files).rev() {
+            let from = self.rotated(index);
+            if from.exists() {
+                std::fs::rename(from, self.rotated(index + 1))?;
+            }
+        }
+        std::fs::rename(&self.path, self.rotated(1))
+    }
+
+    fn write_line(&mut self, line: &[u8]) -> std::io::Result<()> {
+        if self
+            .current
+            .as_ref()
+            .is_some_and(|(_, size)| *size > 0 && size + line.len() as u64 > self.max_size)
+        {
+            self.rotate()?;
+        }
+        let (file, size) = match self.current {
+            Some(ref mut current) => current,
+            None => {
+                let file = File::options().create(true).append(true).open(&self.path)?;
+                let size = file.metadata()?.len();
+                self.current.insert((file, size))
+            }
+        };
+        file.write_all(line)?;
+        *size += line.len() as u64;
+        Ok(())
+    }
+}
+
+fn remove_if_exists(path: &Path) -> std::io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+/// Where the local exporters write their batches
+#[derive(Clone, Debug)]
+pub(super) enum Output {
+    Stdout,
+    File(Arc<Mutex<RotatingFile>>),
+}
+
+impl Output {
+    fn write<T: serde::Serialize>(&self, request: &T) -> OTelSdkResult {
+        let mut line = serde_json::to_vec(request)
+            .map_err(|err| OTelSdkError::InternalFailure(err.to_string()))?;
+        line.push(b'\n');
+        let result = match self {
+            Self::Stdout => std::io::stdout().lock().write_all(&line),
+            Self::File(file) => file
+                .lock()
+                .map_err(|_| OTelSdkError::InternalFailure("file lock poisoned".into()))?
+                .write_line(&line),
+        };
+        result.map_err(|err| OTelSdkError::InternalFailure(err.to_string()))
+    }
+
+    fn flush(&self) -> OTelSdkResult {
+        let result = match self {
+            Self::Stdout => std::io::stdout().lock().flush(),
+            Self::File(file) => match file
+                .lock()
+                .map_err(|_| OTelSdkError::InternalFailure("file lock poisoned".into()))?
+                .current
+            {
+                Some((ref mut file, _)) => file.flush(),
+                None => Ok(()),
+            },
+        };
+        result.map_err(|err| OTelSdkError::InternalFailure(err.to_string()))
+    }
+}
+
+#[derive(Debug)]
+pub(super) struct JsonMetricExporter {
+    output: Output,
+    temporality: Temporality,
+}
+
+impl JsonMetricExporter {
+    pub(super) fn new(output: Output, temporality: Temporality) -> Self {
+        Self {
+            output,
+            temporality,
+        }
+    }
+}
+
+impl PushMetricExporter for JsonMetricExporter {
+    async fn export(&self, metrics: &ResourceMetrics) -> OTelSdkResult {
+        self.output
+            .write(&ExportMetricsServiceRequest::from(metrics))
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.output.flush()
+    }
+
+    fn shutdown_with_timeout(&self, _timeout: Duration) -> OTelSdkResult {
+        self.output.flush()
+    }
+
+    fn temporality(&self) -> Temporality {
+        self.temporality
+    }
+}
+
+#[derive(Debug)]
+pub(super) struct JsonSpanExporter {
+    output: Output,
+    resource: ResourceAttributesWithSchema,
+}
+
+impl JsonSpanExporter {
+    pub(super) fn new(output: Output) -> Self {
+        Self {
+            output,
+            resource: Default::default(),
+        }
+    }
+}
+
+impl SpanExporter for JsonSpanExporter {
+    async fn export(&self, batch: Vec<SpanData>) -> OTelSdkResult {
+        self.output.write(&ExportTraceServiceRequest {
+            resource_spans: group_spans_by_resource_and_scope(batch, &self.resource),
+        })
+    }
+
+    fn force_flush(&mut self) -> OTelSdkResult {
+        self.output.flush()
+    }
+
+    fn shutdown_with_timeout(&mut self, _timeout: Duration) -> OTelSdkResult {
+        self.output.flush()
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.resource = resource.into();
+    }
+}
+
+#[derive(Debug)]
+pub(super) struct JsonLogExporter {
+    output: Output,
+    resource: ResourceAttributesWithSchema,
+}
+
+impl JsonLogExporter {
+    pub(super) fn new(output: Output) -> Self {
+        Self {
+            output,
+            resource: Default::default(),
+        }
+    }
+}
+
+impl LogExporter for JsonLogExporter {
+    async fn export(&self, batch: LogBatch<'_>) -> OTelSdkResult {
+        self.output.write(&ExportLogsServiceRequest {
+            resource_logs: group_logs_by_resource_and_scope(batch, &self.resource),
+        })
+    }
+
+    fn shutdown_with_timeout(&self, _timeout: Duration) -> OTelSdkResult {
+        self.output.flush()
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.resource = resource.into();
+    }
+}
@@ -0,0 +1,80 @@
+use std::str::FromStr;
+
+use anyhow::Context;
+use tracing_subscriber::{Layer, filter::LevelFilter, registry::LookupSpan};
+
+/// How the logs are printed
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human readable lines on stdout
+    #[default]
+    Text,
+    /// One JSON object per line on stdout, with the fields of the current spans
+    Json,
+    /// Sent to the journald socket with the fields as journal entries
+    Journald,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            "journald" => Ok(Self::Journald),
+            other => Err(anyhow::anyhow!(
+                "unknown log format {other:?}, expected text, json or journald"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct LogConfig {
+    pub format: LogFormat,
+    /// Level of the application code, `RUST_LOG` applies when not set
+    pub level: Option<LevelFilter>,
+    /// Level of what is printed on stdout or sent to journald
+    pub stdout_level: Option<LevelFilter>,
+    /// Level of what is sent through the OpenTelemetry log bridge
+    pub otlp_level: Option<LevelFilter>,
+}
+
+impl crate::Configurable for LogConfig {
+    fn from_source(source: &crate::config::Source) -> Self {
+        Self {
+            format: source.parse("LOG_FORMAT", "log.format").unwrap_or_default(),
+            level: source.parse("LOG_LEVEL", "log.level"),
+            stdout_level: source.parse("LOG_STDOUT_LEVEL", "log.stdout_level"),
+            otlp_level: source.parse("LOG_OTLP_LEVEL", "log.otlp_level"),
+        }
+    }
+}
+
+impl LogConfig {
+    /// Layer printing the logs, depending on the format
+    pub(super) fn output_layer<S>(&self) -> anyhow::Result<Box<dyn Layer<S> + Send + Sync>>
+    where
+        S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let level = self.stdout_level.unwrap_or(LevelFilter::TRACE);
+        Ok(match self.format {
+            LogFormat::Text => tracing_subscriber::fmt::layer().with_filter(level).boxed(),
+            LogFormat::Json => tracing_subscriber::fmt::layer()
+                .json()
+                .with_current_span(true)
+                .with_span_list(true)
+                .with_filter(level)
+                .boxed(),
+            LogFormat::Journald => tracing_journald::layer()
+                .context("unable to connect to journald")?
+                .with_filter(level)
+                .boxed(),
+        })
+    }
+
+    pub(super) fn otlp_filter(&self) -> LevelFilter {
+        self.otlp_level.unwrap_or(LevelFilter::TRACE)
+    }
+}
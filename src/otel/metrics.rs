@@ -0,0 +1,82 @@
+use std::{str::FromStr, time::Duration};
+
+use opentelemetry_sdk::metrics::Temporality;
+
+use crate::config::Source;
+
+/// How often and how the metrics are pushed to the exporter
+#[derive(Debug)]
+pub struct MetricExportConfig {
+    /// Time between two exports
+    pub interval: Duration,
+    /// Maximum time an export to the collector can take
+    pub timeout: Duration,
+    /// Delta is preferred by some backends, prometheus always stays cumulative
+    pub temporality: Temporality,
+}
+
+impl Default for MetricExportConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(60),
+            timeout: Duration::from_secs(30),
+            temporality: Temporality::Cumulative,
+        }
+    }
+}
+
+/// Reads a duration, zero is reported as the reader would spin or every export would fail
+fn parse_millis(source: &Source, env: &str, key: &str) -> Option<Duration> {
+    match source.parse(env, key)? {
+        0 => {
+            source.report(env, key, "should be strictly positive");
+            None
+        }
+        millis => Some(Duration::from_millis(millis)),
+    }
+}
+
+/// Parsing wrapper, the temporality is defined by the SDK
+struct TemporalityPreference(Temporality);
+
+impl FromStr for TemporalityPreference {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "cumulative" => Ok(Self(Temporality::Cumulative)),
+            "delta" => Ok(Self(Temporality::Delta)),
+            "lowmemory" => Ok(Self(Temporality::LowMemory)),
+            other => Err(anyhow::anyhow!(
+                "unknown temporality {other:?}, expected cumulative, delta or lowmemory"
+            )),
+        }
+    }
+}
+
+impl crate::Configurable for MetricExportConfig {
+    /// Uses the variables defined by the OpenTelemetry specification, durations are in milliseconds.
+    fn from_source(source: &Source) -> Self {
+        let defaults = Self::default();
+        Self {
+            interval: parse_millis(
+                source,
+                "OTEL_METRIC_EXPORT_INTERVAL",
+                "telemetry.metrics.export_interval",
+            )
+            .unwrap_or(defaults.interval),
+            timeout: parse_millis(
+                source,
+                "OTEL_METRIC_EXPORT_TIMEOUT",
+                "telemetry.metrics.export_timeout",
+            )
+            .unwrap_or(defaults.timeout),
+            temporality: source
+                .parse::<TemporalityPreference>(
+                    "OTEL_EXPORTER_OTLP_METRICS_TEMPORALITY_PREFERENCE",
+                    "telemetry.metrics.temporality",
+                )
+                .map_or(defaults.temporality, |value| value.0),
+        }
+    }
+}
@@ -0,0 +1,492 @@
+use std::{
+    borrow::Cow,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use opentelemetry::{
+    InstrumentationScope, KeyValue, metrics::MeterProvider, trace::TracerProvider,
+};
+use opentelemetry_sdk::{
+    Resource,
+    logs::SdkLoggerProvider,
+    metrics::{PeriodicReader, SdkMeterProvider, exporter::PushMetricExporter},
+    resource::EnvResourceDetector,
+    trace::{BatchSpanProcessor, SdkTracerProvider},
+};
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::{
+    Layer, filter::LevelFilter, layer::SubscriberExt, util::SubscriberInitExt,
+};
+
+use crate::{config::Source, health::Health, http::HttpServer};
+
+mod buffer;
+mod local;
+mod logging;
+mod metrics;
+mod monitored;
+mod prometheus;
+mod resource;
+mod sampling;
+mod transport;
+
+use buffer::BufferedMetricExporter;
+pub use buffer::MetricBufferConfig;
+pub use local::FileExporterConfig;
+pub use logging::LogConfig;
+pub use metrics::MetricExportConfig;
+use monitored::Monitored;
+pub use prometheus::PrometheusConfig;
+pub use sampling::TraceSampler;
+use transport::{Endpoint, OtlpSignal, Transport};
+pub use transport::{OtlpHeaders, OtlpProtocol, OtlpSignalConfig, OtlpTlsConfig};
+
+/// Where the telemetry is exported
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TelemetryMode {
+    /// Sent to the OpenTelemetry collector
+    #[default]
+    Otlp,
+    /// Metrics and spans printed as JSON lines on stdout
+    Stdout,
+    /// Every signal written as JSON lines in rotating files
+    File,
+    /// Nothing is exported, only the logs are printed
+    None,
+}
+
+impl FromStr for TelemetryMode {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "otlp" => Ok(Self::Otlp),
+            "stdout" => Ok(Self::Stdout),
+            "file" => Ok(Self::File),
+            "none" => Ok(Self::None),
+            other => Err(anyhow::anyhow!(
+                "unknown telemetry mode {other:?}, expected otlp, stdout, file or none"
+            )),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct OtelConfig {
+    pub mode: TelemetryMode,
+    pub file: FileExporterConfig,
+    pub endpoint: Option<Cow<'static, str>>,
+    pub protocol: OtlpProtocol,
+    pub metrics: OtlpSignalConfig,
+    pub metric_export: MetricExportConfig,
+    pub buffer: MetricBufferConfig,
+    pub traces: OtlpSignalConfig,
+    pub sampler: TraceSampler,
+    pub logs: OtlpSignalConfig,
+    pub headers: OtlpHeaders,
+    pub tls: OtlpTlsConfig,
+    pub prometheus: Option<PrometheusConfig>,
+    pub log: LogConfig,
+    pub environment: Cow<'static, str>,
+    /// Level of the libraries used to export the telemetry
+    pub inner_level: LevelFilter,
+    pub service_name: Cow<'static, str>,
+    pub service_version: Cow<'static, str>,
+}
+
+impl crate::Configurable for OtelConfig {
+    fn from_source(source: &Source) -> Self {
+        Self {
+            mode: source
+                .parse("TELEMETRY_MODE", "telemetry.mode")
+                .unwrap_or_default(),
+            file: FileExporterConfig::from_source(source),
+            endpoint: source
+                .parse::<Endpoint>(
+                    match std::env::var_os("OTEL_COLLECTOR_ENDPOINT") {
+                        Some(_) => "OTEL_COLLECTOR_ENDPOINT",
+                        None => "OTEL_EXPORTER_OTLP_ENDPOINT",
+                    },
+                    "telemetry.otlp.endpoint",
+                )
+                .map(Endpoint::into_inner),
+            protocol: source
+                .parse("OTEL_EXPORTER_OTLP_PROTOCOL", "telemetry.otlp.protocol")
+                .unwrap_or_default(),
+            metrics: OtlpSignalConfig::from_source(source, "METRICS", "metrics"),
+            metric_export: MetricExportConfig::from_source(source),
+            buffer: MetricBufferConfig::from_source(source),
+            traces: OtlpSignalConfig::from_source(source, "TRACES", "traces"),
+            sampler: TraceSampler::from_source(source),
+            logs: OtlpSignalConfig::from_source(source, "LOGS", "logs"),
+            headers: OtlpHeaders::from_source(source),
+            tls: OtlpTlsConfig::from_source(source),
+            prometheus: PrometheusConfig::from_source(source),
+            log: LogConfig::from_source(source),
+            environment: source
+                .string("ENV", "telemetry.environment")
+                .map(Cow::Owned)
+                .unwrap_or(Cow::Borrowed("local")),
+            inner_level: source
+                .parse("OTEL_INNER_LEVEL", "log.inner_level")
+                .unwrap_or(LevelFilter::ERROR),
+            service_name: source
+                .string("SERVICE_NAME", "telemetry.service_name")
+                .map(Cow::Owned)
+                .unwrap_or(Cow::Borrowed(env!("CARGO_PKG_NAME"))),
+            service_version: source
+                .string("SERVICE_VERSION", "telemetry.service_version")
+                .map(Cow::Owned)
+                .unwrap_or(Cow::Borrowed(env!("CARGO_PKG_VERSION"))),
+        }
+    }
+}
+
+impl OtelConfig {
+    fn inner_filter(&self) -> anyhow::Result<tracing_subscriber::EnvFilter> {
+        let inner = [
+            "h2",
+            "hyper_util",
+            "opentelemetry",
+            "reqwest",
+            "tonic",
+            "tower",
+        ]
+        .map(|target| format!("{target}={}", self.inner_level));
+        let own = self
+            .log
+            .level
+            .map(|level| format!("{}={level}", env!("CARGO_CRATE_NAME")));
+        own.into_iter().chain(inner).try_fold(
+            tracing_subscriber::EnvFilter::from_default_env(),
+            |filter, directive| {
+                let parsed = directive
+                    .parse()
+                    .with_context(|| format!("invalid log directive {directive:?}"))?;
+                Ok(filter.add_directive(parsed))
+            },
+        )
+    }
+
+    /// Detected once as the instance id must be the same for every signal.
+    ///
+    /// The attributes from `OTEL_RESOURCE_ATTRIBUTES` are applied last so they can override
+    /// anything, like `site.name=home`.
+    fn resources(&self) -> Resource {
+        use opentelemetry_semantic_conventions::resource;
+
+        Resource::builder()
+            .with_attribute(KeyValue::new(
+                resource::SERVICE_NAME,
+                self.service_name.to_string(),
+            ))
+            .with_attribute(KeyValue::new(
+                resource::SERVICE_VERSION,
+                self.service_version.to_string(),
+            ))
+            .with_attribute(KeyValue::new(
+                "deployment.environment",
+                self.environment.clone(),
+            ))
+            .with_attributes(self::resource::detect(&self.service_name))
+            .with_detector(Box::new(EnvResourceDetector::new()))
+            .build()
+    }
+
+    /// Merges the signal overrides with the global configuration.
+    ///
+    /// Like in the OpenTelemetry specification, a signal specific endpoint is used as is
+    /// while the signal path is appended to the global endpoint when using HTTP.
+    fn signal(&self, signal: &OtlpSignalConfig, path: &str) -> OtlpSignal {
+        let protocol = signal.protocol.unwrap_or(self.protocol);
+        let endpoint = match (&signal.endpoint, &self.endpoint) {
+            (Some(endpoint), _) => endpoint.to_string(),
+            (None, endpoint) => {
+                let endpoint = endpoint
+                    .as_deref()
+                    .unwrap_or_else(|| protocol.default_endpoint());
+                match protocol {
+                    OtlpProtocol::Grpc => endpoint.to_string(),
+                    OtlpProtocol::HttpProtobuf | OtlpProtocol::HttpJson => {
+                        format!("{}/{path}", endpoint.trim_end_matches('/'))
+                    }
+                }
+            }
+        };
+        OtlpSignal { endpoint, protocol }
+    }
+
+    fn periodic_reader<E: PushMetricExporter>(&self, exporter: E) -> PeriodicReader<E> {
+        PeriodicReader::builder(exporter)
+            .with_interval(self.metric_export.interval)
+            .build()
+    }
+
+    fn setup_metrics(
+        &self,
+        transport: &Transport,
+        resource: &Resource,
+        health: &Health,
+    ) -> anyhow::Result<(SdkMeterProvider, Option<HttpServer>)> {
+        let temporality = self.metric_export.temporality;
+        let mut builder = SdkMeterProvider::builder().with_resource(resource.clone());
+        let buffer = match self.mode {
+            TelemetryMode::Otlp => self.buffer.open()?,
+            _ => None,
+        };
+        builder = match (self.mode, &buffer) {
+            (TelemetryMode::Otlp, Some(buffer)) => {
+                let exporter = BufferedMetricExporter::new(
+                    self.otlp_metrics(transport)?,
+                    buffer.clone(),
+                    transport,
+                    &self.signal(&self.metrics, "v1/metrics"),
+                    self.metric_export.timeout,
+                )?;
+                builder.with_reader(self.periodic_reader(Monitored::new(
+                    exporter,
+                    health,
+                    "otel.export.metrics",
+                )))
+            }
+            (TelemetryMode::Otlp, None) => builder.with_reader(self.periodic_reader(
+                Monitored::new(self.otlp_metrics(transport)?, health, "otel.export.metrics"),
+            )),
+            (TelemetryMode::Stdout, _) => builder.with_reader(self.periodic_reader(
+                local::JsonMetricExporter::new(local::Output::Stdout, temporality),
+            )),
+            (TelemetryMode::File, _) => builder.with_reader(self.periodic_reader(
+                local::JsonMetricExporter::new(self.file.output("metrics")?, temporality),
+            )),
+            (TelemetryMode::None, _) => builder,
+        };
+        let mut server = None;
+        if let Some(ref prometheus) = self.prometheus {
+            let (reader, prometheus_server) = prometheus.install()?;
+            builder = builder.with_reader(reader);
+            server = Some(prometheus_server);
+        }
+        let provider = builder.build();
+        if let Some(buffer) = buffer {
+            buffer.register(&provider.meter("telemetry"));
+        }
+
+        opentelemetry::global::set_meter_provider(provider.clone());
+
+        Ok((provider, server))
+    }
+
+    fn otlp_metrics(
+        &self,
+        transport: &Transport,
+    ) -> anyhow::Result<opentelemetry_otlp::MetricExporter> {
+        use opentelemetry_otlp::{MetricExporter, WithExportConfig};
+
+        let signal = self.signal(&self.metrics, "v1/metrics");
+        let temporality = self.metric_export.temporality;
+        let timeout = self.metric_export.timeout;
+        Ok(match signal.protocol {
+            OtlpProtocol::Grpc => transport
+                .configure_tonic(MetricExporter::builder().with_tonic(), &signal)?
+                .with_timeout(timeout)
+                .with_temporality(temporality)
+                .build()?,
+            OtlpProtocol::HttpProtobuf | OtlpProtocol::HttpJson => transport
+                .configure_http(MetricExporter::builder().with_http(), &signal)?
+                .with_timeout(timeout)
+                .with_temporality(temporality)
+                .build()?,
+        })
+    }
+
+    fn otlp_spans(
+        &self,
+        transport: &Transport,
+    ) -> anyhow::Result<opentelemetry_otlp::SpanExporter> {
+        use opentelemetry_otlp::SpanExporter;
+
+        let signal = self.signal(&self.traces, "v1/traces");
+        Ok(match signal.protocol {
+            OtlpProtocol::Grpc => transport
+                .configure_tonic(SpanExporter::builder().with_tonic(), &signal)?
+                .build()?,
+            OtlpProtocol::HttpProtobuf | OtlpProtocol::HttpJson => transport
+                .configure_http(SpanExporter::builder().with_http(), &signal)?
+                .build()?,
+        })
+    }
+
+    fn otlp_logs(&self, transport: &Transport) -> anyhow::Result<opentelemetry_otlp::LogExporter> {
+        use opentelemetry_otlp::LogExporter;
+
+        let signal = self.signal(&self.logs, "v1/logs");
+        Ok(match signal.protocol {
+            OtlpProtocol::Grpc => transport
+                .configure_tonic(LogExporter::builder().with_tonic(), &signal)?
+                .build()?,
+            OtlpProtocol::HttpProtobuf | OtlpProtocol::HttpJson => transport
+                .configure_http(LogExporter::builder().with_http(), &signal)?
+                .build()?,
+        })
+    }
+
+    fn tracer_provider(
+        &self,
+        transport: &Transport,
+        resource: &Resource,
+        health: &Health,
+    ) -> anyhow::Result<Option<SdkTracerProvider>> {
+        let span_processor = match self.mode {
+            TelemetryMode::Otlp => BatchSpanProcessor::builder(Monitored::new(
+                self.otlp_spans(transport)?,
+                health,
+                "otel.export.traces",
+            ))
+            .build(),
+            TelemetryMode::Stdout => {
+                BatchSpanProcessor::builder(local::JsonSpanExporter::new(local::Output::Stdout))
+                    .build()
+            }
+            TelemetryMode::File => BatchSpanProcessor::builder(local::JsonSpanExporter::new(
+                self.file.output("traces")?,
+            ))
+            .build(),
+            TelemetryMode::None => return Ok(None),
+        };
+
+        let builder = SdkTracerProvider::builder()
+            .with_sampler(self.sampler.sampler())
+            .with_resource(resource.clone());
+        let builder = if self.sampler.filters_spans() {
+            builder.with_span_processor(sampling::SupportedOrErrorProcessor::new(span_processor))
+        } else {
+            builder.with_span_processor(span_processor)
+        };
+        Ok(Some(builder.build()))
+    }
+
+    /// Logs are already printed by the `fmt` layer, they are only exported to the collector
+    /// or to a file.
+    fn logger_provider(
+        &self,
+        transport: &Transport,
+        resource: &Resource,
+        health: &Health,
+    ) -> anyhow::Result<Option<SdkLoggerProvider>> {
+        let builder = SdkLoggerProvider::builder().with_resource(resource.clone());
+        let builder = match self.mode {
+            TelemetryMode::Otlp => builder.with_batch_exporter(Monitored::new(
+                self.otlp_logs(transport)?,
+                health,
+                "otel.export.logs",
+            )),
+            TelemetryMode::File => {
+                builder.with_batch_exporter(local::JsonLogExporter::new(self.file.output("logs")?))
+            }
+            TelemetryMode::Stdout | TelemetryMode::None => return Ok(None),
+        };
+        Ok(Some(builder.build()))
+    }
+
+    fn setup_traces(
+        &self,
+        transport: &Transport,
+        resource: &Resource,
+        health: &Health,
+    ) -> anyhow::Result<(Option<SdkTracerProvider>, Option<SdkLoggerProvider>)> {
+        let tracer_provider = self.tracer_provider(transport, resource, health)?;
+        let telemetry = tracer_provider.as_ref().map(|tracer_provider| {
+            let scope = InstrumentationScope::builder(self.service_name.to_string())
+                .with_version(self.service_version.to_string())
+                .with_schema_url(opentelemetry_semantic_conventions::SCHEMA_URL)
+                .with_attributes(None)
+                .build();
+            let tracer = tracer_provider.tracer_with_scope(scope);
+
+            opentelemetry::global::set_tracer_provider(tracer_provider.clone());
+
+            OpenTelemetryLayer::new(tracer)
+        });
+
+        let logger_provider = self.logger_provider(transport, resource, health)?;
+        let otel_layer = logger_provider.as_ref().map(|log_provider| {
+            opentelemetry_appender_tracing::layer::OpenTelemetryTracingBridge::new(log_provider)
+                .with_filter(self.log.otlp_filter())
+        });
+
+        tracing_subscriber::registry()
+            .with(self.inner_filter()?)
+            .with(telemetry)
+            .with(otel_layer)
+            .with(self.log.output_layer()?)
+            .try_init()?;
+
+        Ok((tracer_provider, logger_provider))
+    }
+
+    /// Installs the global providers, the returned guard must be shut down before exiting
+    /// to export what is still buffered.
+    ///
+    /// The outcome of the exports to the collector is reported to the health endpoints. The
+    /// prometheus scrape endpoint, when enabled, is returned to be served by the supervisor.
+    pub(crate) fn install(
+        &self,
+        health: &Health,
+    ) -> anyhow::Result<(Telemetry, Option<HttpServer>)> {
+        let transport = Transport::new(&self.tls, &self.headers)?;
+        let resource = self.resources();
+        let (meter_provider, server) = self.setup_metrics(&transport, &resource, health)?;
+        let (tracer_provider, logger_provider) =
+            self.setup_traces(&transport, &resource, health)?;
+        let telemetry = Telemetry {
+            meter_provider,
+            tracer_provider,
+            logger_provider,
+        };
+        Ok((telemetry, server))
+    }
+}
+
+/// Handles of the installed providers, kept to flush them before exiting
+#[derive(Debug)]
+pub struct Telemetry {
+    meter_provider: SdkMeterProvider,
+    tracer_provider: Option<SdkTracerProvider>,
+    logger_provider: Option<SdkLoggerProvider>,
+}
+
+impl Telemetry {
+    /// Exports the pending spans, logs and metrics then stops the providers.
+    ///
+    /// This blocks until the exporters are done or the timeout is reached.
+    pub fn shutdown(self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        let remaining = || deadline.saturating_duration_since(Instant::now());
+
+        if let Some(ref provider) = self.tracer_provider
+            && let Err(err) = provider.shutdown_with_timeout(remaining())
+        {
+            tracing::warn!(
+                message = "unable to shut down tracer provider",
+                error.message = err.to_string(),
+            );
+        }
+        if let Err(err) = self.meter_provider.shutdown_with_timeout(remaining()) {
+            tracing::warn!(
+                message = "unable to shut down meter provider",
+                error.message = err.to_string(),
+            );
+        }
+        // the logs are stopped last so that the warnings above are still exported
+        if let Some(ref provider) = self.logger_provider
+            && let Err(err) = provider.shutdown_with_timeout(remaining())
+        {
+            tracing::warn!(
+                message = "unable to shut down logger provider",
+                error.message = err.to_string(),
+            );
+        }
+    }
+}
@@ -0,0 +1,85 @@
+//! Exporters reporting the outcome of every export to the health endpoints.
+
+use std::time::Duration;
+
+use opentelemetry_sdk::{
+    Resource,
+    error::OTelSdkResult,
+    logs::{LogBatch, LogExporter},
+    metrics::{Temporality, data::ResourceMetrics, exporter::PushMetricExporter},
+    trace::{SpanData, SpanExporter},
+};
+
+use crate::health::Health;
+
+#[derive(Debug)]
+pub(super) struct Monitored<E> {
+    inner: E,
+    health: Health,
+    name: &'static str,
+}
+
+impl<E> Monitored<E> {
+    pub(super) fn new(inner: E, health: &Health, name: &'static str) -> Self {
+        Self {
+            inner,
+            health: health.clone(),
+            name,
+        }
+    }
+
+    fn record(&self, result: OTelSdkResult) -> OTelSdkResult {
+        self.health.record(self.name, &result);
+        result
+    }
+}
+
+impl<E: PushMetricExporter> PushMetricExporter for Monitored<E> {
+    async fn export(&self, metrics: &ResourceMetrics) -> OTelSdkResult {
+        self.record(self.inner.export(metrics).await)
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.inner.force_flush()
+    }
+
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        self.inner.shutdown_with_timeout(timeout)
+    }
+
+    fn temporality(&self) -> Temporality {
+        self.inner.temporality()
+    }
+}
+
+impl<E: SpanExporter> SpanExporter for Monitored<E> {
+    async fn export(&self, batch: Vec<SpanData>) -> OTelSdkResult {
+        self.record(self.inner.export(batch).await)
+    }
+
+    fn force_flush(&mut self) -> OTelSdkResult {
+        self.inner.force_flush()
+    }
+
+    fn shutdown_with_timeout(&mut self, timeout: Duration) -> OTelSdkResult {
+        self.inner.shutdown_with_timeout(timeout)
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.inner.set_resource(resource);
+    }
+}
+
+impl<E: LogExporter> LogExporter for Monitored<E> {
+    async fn export(&self, batch: LogBatch<'_>) -> OTelSdkResult {
+        self.record(self.inner.export(batch).await)
+    }
+
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        self.inner.shutdown_with_timeout(timeout)
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.inner.set_resource(resource);
+    }
+}
@@ -0,0 +1,324 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    net::SocketAddr,
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+use opentelemetry::KeyValue;
+use opentelemetry_sdk::{
+    error::OTelSdkResult,
+    metrics::{
+        InstrumentKind, ManualReader, Pipeline, Temporality,
+        data::{AggregatedMetrics, MetricData, ResourceMetrics},
+        reader::MetricReader,
+    },
+};
+
+use crate::http::HttpServer;
+
+#[derive(Debug)]
+pub struct PrometheusConfig {
+    pub address: SocketAddr,
+}
+
+impl PrometheusConfig {
+    pub(super) fn from_source(source: &crate::config::Source) -> Option<Self> {
+        source
+            .parse(
+                "PROMETHEUS_LISTEN_ADDRESS",
+                "telemetry.prometheus.listen_address",
+            )
+            .map(|address| Self { address })
+    }
+
+    /// Creates the reader to register in the meter provider and the server exposing it
+    pub(super) fn install(&self) -> anyhow::Result<(PrometheusReader, HttpServer)> {
+        let reader = PrometheusReader::default();
+        let router = axum::Router::new()
+            .route("/metrics", axum::routing::get(handle_metrics))
+            .with_state(reader.clone());
+        let server = HttpServer::bind("prometheus-server", self.address, router)?;
+        Ok((reader, server))
+    }
+}
+
+async fn handle_metrics(
+    axum::extract::State(reader): axum::extract::State<PrometheusReader>,
+) -> impl axum::response::IntoResponse {
+    match reader.render() {
+        Ok(body) => (
+            axum::http::StatusCode::OK,
+            [(
+                axum::http::header::CONTENT_TYPE,
+                "text/plain; version=0.0.4; charset=utf-8",
+            )],
+            body,
+        ),
+        Err(err) => (
+            axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+            [(
+                axum::http::header::CONTENT_TYPE,
+                "text/plain; charset=utf-8",
+            )],
+            err.to_string(),
+        ),
+    }
+}
+
+/// Pull based reader, collected every time Prometheus scrapes the endpoint
+#[derive(Clone, Debug, Default)]
+pub(super) struct PrometheusReader(Arc<ManualReader>);
+
+impl MetricReader for PrometheusReader {
+    fn register_pipeline(&self, pipeline: Weak<Pipeline>) {
+        self.0.register_pipeline(pipeline);
+    }
+
+    fn collect(&self, rm: &mut ResourceMetrics) -> OTelSdkResult {
+        self.0.collect(rm)
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.0.force_flush()
+    }
+
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        self.0.shutdown_with_timeout(timeout)
+    }
+
+    fn temporality(&self, kind: InstrumentKind) -> Temporality {
+        self.0.temporality(kind)
+    }
+}
+
+#[derive(Default)]
+struct Family {
+    kind: &'static str,
+    help: String,
+    samples: Vec<String>,
+}
+
+impl PrometheusReader {
+    fn render(&self) -> anyhow::Result<String> {
+        let mut metrics = ResourceMetrics::default();
+        self.0.collect(&mut metrics)?;
+
+        let mut families: BTreeMap<String, Family> = BTreeMap::new();
+
+        let target = metrics
+            .resource()
+            .iter()
+            .map(|(key, value)| (sanitize(key.as_str()), value.to_string()))
+            .collect::<Vec<_>>();
+        families.insert(
+            "target_info".into(),
+            Family {
+                kind: "gauge",
+                help: "Target metadata".into(),
+                samples: vec![format!("target_info{} 1", labels(&target))],
+            },
+        );
+
+        for scope in metrics.scope_metrics() {
+            let scope_label = (
+                "otel_scope_name".to_string(),
+                scope.scope().name().to_string(),
+            );
+            for metric in scope.metrics() {
+                let base = metric_name(metric.name(), metric.unit());
+                match metric.data() {
+                    AggregatedMetrics::F64(data) => write_data(
+                        &mut families,
+                        &base,
+                        metric.description(),
+                        data,
+                        &scope_label,
+                    ),
+                    AggregatedMetrics::U64(data) => write_data(
+                        &mut families,
+                        &base,
+                        metric.description(),
+                        data,
+                        &scope_label,
+                    ),
+                    AggregatedMetrics::I64(data) => write_data(
+                        &mut families,
+                        &base,
+                        metric.description(),
+                        data,
+                        &scope_label,
+                    ),
+                }
+            }
+        }
+
+        let mut output = String::new();
+        for (name, family) in families {
+            if !family.help.is_empty() {
+                writeln!(output, "# HELP {name} {}", escape_help(&family.help))?;
+            }
+            writeln!(output, "# TYPE {name} {}", family.kind)?;
+            for sample in family.samples {
+                writeln!(output, "{sample}")?;
+            }
+        }
+        Ok(output)
+    }
+}
+
+fn write_data<T: Copy + std::fmt::Display>(
+    families: &mut BTreeMap<String, Family>,
+    base: &str,
+    description: &str,
+    data: &MetricData<T>,
+    scope_label: &(String, String),
+) {
+    let point_labels = |attributes: &mut dyn Iterator<Item = &KeyValue>| {
+        let mut items = attributes
+            .map(|kv| (sanitize(kv.key.as_str()), kv.value.to_string()))
+            .collect::<Vec<_>>();
+        items.push(scope_label.clone());
+        items
+    };
+    match data {
+        MetricData::Gauge(gauge) => {
+            let family = family(families, base.to_string(), "gauge", description);
+            for point in gauge.data_points() {
+                let labels = labels(&point_labels(&mut point.attributes()));
+                family
+                    .samples
+                    .push(format!("{base}{labels} {}", point.value()));
+            }
+        }
+        MetricData::Sum(sum) => {
+            let (name, kind) = if sum.is_monotonic() {
+                (format!("{base}_total"), "counter")
+            } else {
+                (base.to_string(), "gauge")
+            };
+            let family = family(families, name.clone(), kind, description);
+            for point in sum.data_points() {
+                let labels = labels(&point_labels(&mut point.attributes()));
+                family
+                    .samples
+                    .push(format!("{name}{labels} {}", point.value()));
+            }
+        }
+        MetricData::Histogram(histogram) => {
+            let family = family(families, base.to_string(), "histogram", description);
+            for point in histogram.data_points() {
+                let items = point_labels(&mut point.attributes());
+                let mut cumulative = 0;
+                let bounds = point
+                    .bounds()
+                    .map(|bound| bound.to_string())
+                    .chain(std::iter::once("+Inf".to_string()));
+                for (bound, count) in bounds.zip(point.bucket_counts()) {
+                    cumulative += count;
+                    let mut items = items.clone();
+                    items.push(("le".into(), bound));
+                    family
+                        .samples
+                        .push(format!("{base}_bucket{} {cumulative}", labels(&items)));
+                }
+                let labels = labels(&items);
+                family
+                    .samples
+                    .push(format!("{base}_sum{labels} {}", point.sum()));
+                family
+                    .samples
+                    .push(format!("{base}_count{labels} {}", point.count()));
+            }
+        }
+        MetricData::ExponentialHistogram(_) => {
+            tracing::debug!(
+                message = "exponential histograms are not supported by the prometheus exporter",
+                metric = base,
+            );
+        }
+    }
+}
+
+fn family<'a>(
+    families: &'a mut BTreeMap<String, Family>,
+    name: String,
+    kind: &'static str,
+    description: &str,
+) -> &'a mut Family {
+    let family = families.entry(name).or_insert_with(|| Family {
+        kind,
+        ..Default::default()
+    });
+    if family.help.is_empty() {
+        family.help = description.to_string();
+    }
+    family
+}
+
+/// Converts an OpenTelemetry name and unit to the Prometheus conventions
+fn metric_name(name: &str, unit: &str) -> String {
+    let mut name = sanitize(name);
+    let unit = match unit {
+        "" | "1" => None,
+        "s" => Some("seconds"),
+        "ms" => Some("milliseconds"),
+        "m" => Some("meters"),
+        "V" => Some("volts"),
+        "Cel" => Some("celsius"),
+        "%" => Some("percent"),
+        "lx" => Some("lux"),
+        "uS/cm" => Some("microsiemens_per_centimeter"),
+        "By" => Some("bytes"),
+        // annotations like {device} are dropped
+        other if other.starts_with('{') => None,
+        other => Some(other),
+    };
+    if let Some(unit) = unit.map(sanitize) {
+        let unit = unit.to_lowercase();
+        if !name.ends_with(&format!("_{unit}")) {
+            name.push('_');
+            name.push_str(&unit);
+        }
+    }
+    name
+}
+
+fn sanitize(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    for (index, c) in value.chars().enumerate() {
+        if c.is_ascii_alphanumeric() && !(index == 0 && c.is_ascii_digit()) || c == '_' {
+            result.push(c);
+        } else if index == 0 && c.is_ascii_digit() {
+            result.push('_');
+            result.push(c);
+        } else if !result.ends_with('_') {
+            result.push('_');
+        }
+    }
+    result
+}
+
+fn labels(items: &[(String, String)]) -> String {
+    if items.is_empty() {
+        return String::new();
+    }
+    let inner = items
+        .iter()
+        .map(|(key, value)| format!("{key}=\"{}\"", escape_label(value)))
+        .collect::<Vec<_>>()
+        .join(",");
+    format!("{{{inner}}}")
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn escape_help(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('\n', "\\n")
+}
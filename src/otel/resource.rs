@@ -0,0 +1,59 @@
+//! Attributes describing the host the service runs on.
+//!
+//! Everything is read from `/proc` and `/etc`, a missing file only means a missing attribute.
+
+use opentelemetry::KeyValue;
+use opentelemetry_semantic_conventions::resource;
+
+/// Namespace recommended by the specification to derive `service.instance.id`
+const INSTANCE_ID_NAMESPACE: uuid::Uuid =
+    uuid::Uuid::from_u128(0x4d63009a_8d0f_11ee_aad7_4c796ed8e320);
+
+fn read_trimmed(path: &str) -> Option<String> {
+    let value = std::fs::read_to_string(path).ok()?;
+    // device tree strings are nul terminated
+    let value = value.trim_matches(|c: char| c.is_whitespace() || c == '\0');
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+/// Stable across restarts, the machine id itself is hashed as it shouldn't be exposed
+fn instance_id(service_name: &str) -> String {
+    match read_trimmed("/etc/machine-id") {
+        Some(machine_id) => uuid::Uuid::new_v5(
+            &INSTANCE_ID_NAMESPACE,
+            format!("{machine_id}{service_name}").as_bytes(),
+        ),
+        None => uuid::Uuid::new_v4(),
+    }
+    .to_string()
+}
+
+/// Converts the rust architecture to the values of the semantic conventions
+fn host_arch() -> &'static str {
+    match std::env::consts::ARCH {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        "arm" => "arm32",
+        "powerpc64" => "ppc64",
+        other => other,
+    }
+}
+
+pub(super) fn detect(service_name: &str) -> Vec<KeyValue> {
+    let mut attributes = vec![
+        KeyValue::new(resource::SERVICE_INSTANCE_ID, instance_id(service_name)),
+        KeyValue::new(resource::HOST_ARCH, host_arch()),
+        KeyValue::new(resource::OS_TYPE, std::env::consts::OS),
+    ];
+    if let Some(hostname) = read_trimmed("/proc/sys/kernel/hostname") {
+        attributes.push(KeyValue::new(resource::HOST_NAME, hostname));
+    }
+    if let Some(release) = read_trimmed("/proc/sys/kernel/osrelease") {
+        attributes.push(KeyValue::new(resource::OS_VERSION, release));
+    }
+    // like "Raspberry Pi 4 Model B Rev 1.4", only available on device tree based boards
+    if let Some(model) = read_trimmed("/proc/device-tree/model") {
+        attributes.push(KeyValue::new(resource::DEVICE_MODEL_NAME, model));
+    }
+    attributes
+}
@@ -0,0 +1,153 @@
+use std::{str::FromStr, time::Duration};
+
+use opentelemetry::{Key, Value, trace::Status};
+use opentelemetry_sdk::{
+    Resource,
+    error::OTelSdkResult,
+    trace::{Sampler, Span, SpanData, SpanProcessor},
+};
+
+use crate::config::Source;
+
+/// Span attribute set to `false` on the events of devices no driver handles
+const SUPPORTED_ATTRIBUTE: &str = "ble.supported";
+
+/// Which spans are exported, named like in `OTEL_TRACES_SAMPLER`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TraceSampler {
+    AlwaysOn,
+    AlwaysOff,
+    TraceIdRatio(f64),
+    #[default]
+    ParentBasedAlwaysOn,
+    ParentBasedAlwaysOff,
+    ParentBasedTraceIdRatio(f64),
+    /// Every span is recorded but only the failed ones and the ones of supported devices
+    /// are exported
+    SupportedOrError,
+}
+
+impl FromStr for TraceSampler {
+    type Err = anyhow::Error;
+
+    /// Parses the sampler name, the ratio defaults to 1 until set by [`TraceSampler::with_arg`]
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "always_on" => Ok(Self::AlwaysOn),
+            "always_off" => Ok(Self::AlwaysOff),
+            "traceidratio" => Ok(Self::TraceIdRatio(1.0)),
+            "parentbased_always_on" => Ok(Self::ParentBasedAlwaysOn),
+            "parentbased_always_off" => Ok(Self::ParentBasedAlwaysOff),
+            "parentbased_traceidratio" => Ok(Self::ParentBasedTraceIdRatio(1.0)),
+            "supported_or_error" => Ok(Self::SupportedOrError),
+            other => Err(anyhow::anyhow!(
+                "unknown sampler {other:?}, expected always_on, always_off, traceidratio, \
+                parentbased_always_on, parentbased_always_off, parentbased_traceidratio \
+                or supported_or_error"
+            )),
+        }
+    }
+}
+
+/// Argument of the ratio based samplers
+struct Ratio(f64);
+
+impl FromStr for Ratio {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let ratio = value.parse::<f64>()?;
+        if !(0.0..=1.0).contains(&ratio) {
+            anyhow::bail!("invalid sampler argument {ratio}, expected a ratio between 0 and 1");
+        }
+        Ok(Self(ratio))
+    }
+}
+
+impl TraceSampler {
+    /// Reads `OTEL_TRACES_SAMPLER` and its ratio from `OTEL_TRACES_SAMPLER_ARG`
+    pub(super) fn from_source(source: &Source) -> Self {
+        let sampler: Self = source
+            .parse("OTEL_TRACES_SAMPLER", "telemetry.traces.sampler")
+            .unwrap_or_default();
+        match source.parse::<Ratio>("OTEL_TRACES_SAMPLER_ARG", "telemetry.traces.sampler_arg") {
+            Some(ratio) => sampler.with_arg(ratio.0),
+            None => sampler,
+        }
+    }
+
+    fn with_arg(self, ratio: f64) -> Self {
+        match self {
+            Self::TraceIdRatio(_) => Self::TraceIdRatio(ratio),
+            Self::ParentBasedTraceIdRatio(_) => Self::ParentBasedTraceIdRatio(ratio),
+            other => other,
+        }
+    }
+
+    pub(super) fn sampler(&self) -> Sampler {
+        match self {
+            Self::AlwaysOn | Self::SupportedOrError => Sampler::AlwaysOn,
+            Self::AlwaysOff => Sampler::AlwaysOff,
+            Self::TraceIdRatio(ratio) => Sampler::TraceIdRatioBased(*ratio),
+            Self::ParentBasedAlwaysOn => Sampler::ParentBased(Box::new(Sampler::AlwaysOn)),
+            Self::ParentBasedAlwaysOff => Sampler::ParentBased(Box::new(Sampler::AlwaysOff)),
+            Self::ParentBasedTraceIdRatio(ratio) => {
+                Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(*ratio)))
+            }
+        }
+    }
+
+    /// Whether the spans must go through the [`SupportedOrErrorProcessor`]
+    pub(super) fn filters_spans(&self) -> bool {
+        matches!(self, Self::SupportedOrError)
+    }
+}
+
+/// Drops the spans of unsupported devices once they ended, unless they failed.
+///
+/// This can't be done by a sampler as the outcome isn't known when the span starts.
+#[derive(Debug)]
+pub(super) struct SupportedOrErrorProcessor<P> {
+    inner: P,
+}
+
+impl<P> SupportedOrErrorProcessor<P> {
+    pub(super) fn new(inner: P) -> Self {
+        Self { inner }
+    }
+}
+
+fn is_relevant(span: &SpanData) -> bool {
+    if matches!(span.status, Status::Error { .. }) {
+        return true;
+    }
+    let key = Key::from_static_str(SUPPORTED_ATTRIBUTE);
+    !span
+        .attributes
+        .iter()
+        .any(|kv| kv.key == key && kv.value == Value::Bool(false))
+}
+
+impl<P: SpanProcessor> SpanProcessor for SupportedOrErrorProcessor<P> {
+    fn on_start(&self, span: &mut Span, cx: &opentelemetry::Context) {
+        self.inner.on_start(span, cx);
+    }
+
+    fn on_end(&self, span: SpanData) {
+        if is_relevant(&span) {
+            self.inner.on_end(span);
+        }
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.inner.force_flush()
+    }
+
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        self.inner.shutdown_with_timeout(timeout)
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.inner.set_resource(resource);
+    }
+}
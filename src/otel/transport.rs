@@ -0,0 +1,324 @@
+use std::{borrow::Cow, path::PathBuf, str::FromStr};
+
+use anyhow::Context;
+use opentelemetry_otlp::{
+    WithExportConfig, WithHttpConfig, WithTonicConfig,
+    tonic_types::{
+        metadata::MetadataMap,
+        transport::{Certificate, ClientTlsConfig, Identity},
+    },
+};
+
+use crate::config::Source;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OtlpProtocol {
+    #[default]
+    Grpc,
+    HttpProtobuf,
+    HttpJson,
+}
+
+impl FromStr for OtlpProtocol {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "grpc" => Ok(Self::Grpc),
+            "http/protobuf" => Ok(Self::HttpProtobuf),
+            "http/json" => Ok(Self::HttpJson),
+            other => Err(anyhow::anyhow!(
+                "unknown protocol {other:?}, expected grpc, http/protobuf or http/json"
+            )),
+        }
+    }
+}
+
+/// Address of the collector, only checked to fail when reading the configuration
+pub(super) struct Endpoint(String);
+
+impl FromStr for Endpoint {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let url = reqwest::Url::parse(value).with_context(|| format!("invalid url {value:?}"))?;
+        if !matches!(url.scheme(), "http" | "https") {
+            anyhow::bail!(
+                "unsupported scheme {:?}, expected http or https",
+                url.scheme()
+            );
+        }
+        Ok(Self(value.to_string()))
+    }
+}
+
+impl Endpoint {
+    pub(super) fn into_inner(self) -> Cow<'static, str> {
+        Cow::Owned(self.0)
+    }
+}
+
+impl OtlpProtocol {
+    pub(super) fn default_endpoint(&self) -> &'static str {
+        match self {
+            Self::Grpc => "http://localhost:4317",
+            Self::HttpProtobuf | Self::HttpJson => "http://localhost:4318",
+        }
+    }
+
+    fn as_otlp(&self) -> opentelemetry_otlp::Protocol {
+        match self {
+            Self::Grpc => opentelemetry_otlp::Protocol::Grpc,
+            Self::HttpProtobuf => opentelemetry_otlp::Protocol::HttpBinary,
+            Self::HttpJson => opentelemetry_otlp::Protocol::HttpJson,
+        }
+    }
+}
+
+/// Overrides of the exporter configuration for a single signal
+#[derive(Debug, Default)]
+pub struct OtlpSignalConfig {
+    pub endpoint: Option<Cow<'static, str>>,
+    pub protocol: Option<OtlpProtocol>,
+}
+
+impl OtlpSignalConfig {
+    /// Reads `OTEL_EXPORTER_OTLP_{SIGNAL}_*` or the `telemetry.otlp.{signal}` table
+    pub(super) fn from_source(source: &Source, env: &str, key: &str) -> Self {
+        Self {
+            endpoint: source
+                .parse::<Endpoint>(
+                    &format!("OTEL_EXPORTER_OTLP_{env}_ENDPOINT"),
+                    &format!("telemetry.otlp.{key}.endpoint"),
+                )
+                .map(Endpoint::into_inner),
+            protocol: source.parse(
+                &format!("OTEL_EXPORTER_OTLP_{env}_PROTOCOL"),
+                &format!("telemetry.otlp.{key}.protocol"),
+            ),
+        }
+    }
+}
+
+/// Exporter configuration of a signal, once merged with the global one
+#[derive(Debug)]
+pub(super) struct OtlpSignal {
+    pub endpoint: String,
+    pub protocol: OtlpProtocol,
+}
+
+/// Headers sent with every export request, values are hidden when debugging
+#[derive(Clone, Default)]
+pub struct OtlpHeaders(pub Vec<(String, String)>);
+
+impl std::fmt::Debug for OtlpHeaders {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_map()
+            .entries(self.0.iter().map(|(key, _)| (key, "***")))
+            .finish()
+    }
+}
+
+impl OtlpHeaders {
+    /// Reads the headers from `OTEL_EXPORTER_OTLP_HEADERS`, formatted like `key1=value1,key2=value2`,
+    /// and the authentication shortcuts.
+    pub(super) fn from_source(source: &Source) -> Self {
+        let mut headers: Vec<_> = source
+            .parse_list::<Header>("OTEL_EXPORTER_OTLP_HEADERS", "telemetry.otlp.headers")
+            .unwrap_or_default()
+            .into_iter()
+            .map(|header| (header.key, header.value))
+            .collect();
+        if let Some(value) = source.string(
+            "OTEL_COLLECTOR_AUTHORIZATION",
+            "telemetry.otlp.authorization",
+        ) {
+            headers.push(("authorization".into(), value));
+        }
+        if let Some(value) = source.string("OTEL_COLLECTOR_API_KEY", "telemetry.otlp.api_key") {
+            let key = source
+                .string(
+                    "OTEL_COLLECTOR_API_KEY_HEADER",
+                    "telemetry.otlp.api_key_header",
+                )
+                .unwrap_or_else(|| String::from("x-api-key"));
+            headers.push((key.to_lowercase(), value));
+        }
+        Self(headers)
+    }
+}
+
+/// Header formatted like `key=value`, the value being percent encoded
+struct Header {
+    key: String,
+    value: String,
+}
+
+impl FromStr for Header {
+    type Err = anyhow::Error;
+
+    fn from_str(item: &str) -> Result<Self, Self::Err> {
+        let (key, value) = item
+            .split_once('=')
+            .context("invalid header, expected key=value")?;
+        Ok(Self {
+            key: key.trim().to_lowercase(),
+            value: percent_decode(value.trim())?,
+        })
+    }
+}
+
+fn percent_decode(value: &str) -> anyhow::Result<String> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut iter = value.bytes();
+    while let Some(byte) = iter.next() {
+        if byte == b'%' {
+            let high = iter.next().and_then(|c| (c as char).to_digit(16));
+            let low = iter.next().and_then(|c| (c as char).to_digit(16));
+            let (Some(high), Some(low)) = (high, low) else {
+                anyhow::bail!("invalid percent encoding");
+            };
+            bytes.push((high * 16 + low) as u8);
+        } else {
+            bytes.push(byte);
+        }
+    }
+    String::from_utf8(bytes).context("invalid utf-8 in percent encoded value")
+}
+
+/// Certificates used to connect to the collector
+#[derive(Debug, Default)]
+pub struct OtlpTlsConfig {
+    /// Certificate authority used to verify the collector, in PEM format
+    pub certificate: Option<PathBuf>,
+    /// Client certificate for mutual TLS, in PEM format
+    pub client_certificate: Option<PathBuf>,
+    /// Private key of the client certificate, in PEM format
+    pub client_key: Option<PathBuf>,
+}
+
+impl crate::Configurable for OtlpTlsConfig {
+    fn from_source(source: &Source) -> Self {
+        Self {
+            certificate: source.parse(
+                "OTEL_EXPORTER_OTLP_CERTIFICATE",
+                "telemetry.otlp.certificate",
+            ),
+            client_certificate: source.parse(
+                "OTEL_EXPORTER_OTLP_CLIENT_CERTIFICATE",
+                "telemetry.otlp.client_certificate",
+            ),
+            client_key: source.parse("OTEL_EXPORTER_OTLP_CLIENT_KEY", "telemetry.otlp.client_key"),
+        }
+    }
+}
+
+fn read_pem(path: &PathBuf) -> anyhow::Result<Vec<u8>> {
+    std::fs::read(path).with_context(|| format!("unable to read {path:?}"))
+}
+
+/// Everything needed to reach the collector, loaded once and shared by all the exporters
+#[derive(Debug)]
+pub(super) struct Transport {
+    headers: OtlpHeaders,
+    certificate: Option<Vec<u8>>,
+    identity: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Transport {
+    pub(super) fn new(tls: &OtlpTlsConfig, headers: &OtlpHeaders) -> anyhow::Result<Self> {
+        let certificate = tls.certificate.as_ref().map(read_pem).transpose()?;
+        let identity = match (&tls.client_certificate, &tls.client_key) {
+            (Some(cert), Some(key)) => Some((read_pem(cert)?, read_pem(key)?)),
+            (None, None) => None,
+            _ => anyhow::bail!("both the client certificate and key must be provided"),
+        };
+        Ok(Self {
+            headers: headers.clone(),
+            certificate,
+            identity,
+        })
+    }
+
+    pub(super) fn headers(&self) -> &OtlpHeaders {
+        &self.headers
+    }
+
+    /// Headers sent as gRPC metadata
+    pub(super) fn metadata(&self) -> anyhow::Result<MetadataMap> {
+        let mut metadata = MetadataMap::with_capacity(self.headers.0.len());
+        for (key, value) in self.headers.0.iter() {
+            let key = tonic::metadata::MetadataKey::from_bytes(key.as_bytes())
+                .with_context(|| format!("invalid header name {key:?}"))?;
+            let value = tonic::metadata::MetadataValue::try_from(value.as_str())
+                .with_context(|| format!("invalid header value for {key:?}"))?;
+            metadata.insert(key, value);
+        }
+        Ok(metadata)
+    }
+
+    /// Only used with `https://` endpoints
+    pub(super) fn tonic_tls(&self) -> ClientTlsConfig {
+        let mut tls = ClientTlsConfig::new().with_native_roots();
+        if let Some(ref certificate) = self.certificate {
+            tls = tls.ca_certificate(Certificate::from_pem(certificate));
+        }
+        if let Some((ref cert, ref key)) = self.identity {
+            tls = tls.identity(Identity::from_pem(cert, key));
+        }
+        tls
+    }
+
+    pub(super) fn configure_tonic<B>(&self, builder: B, signal: &OtlpSignal) -> anyhow::Result<B>
+    where
+        B: WithExportConfig + WithTonicConfig,
+    {
+        let mut builder = builder
+            .with_protocol(signal.protocol.as_otlp())
+            .with_endpoint(signal.endpoint.clone())
+            .with_metadata(self.metadata()?);
+
+        if signal.endpoint.starts_with("https://") {
+            builder = builder.with_tls_config(self.tonic_tls());
+        }
+
+        Ok(builder)
+    }
+
+    pub(super) fn configure_http<B>(&self, builder: B, signal: &OtlpSignal) -> anyhow::Result<B>
+    where
+        B: WithExportConfig + WithHttpConfig,
+    {
+        let mut builder = builder
+            .with_protocol(signal.protocol.as_otlp())
+            .with_endpoint(signal.endpoint.clone())
+            .with_headers(self.headers.0.iter().cloned().collect());
+
+        if self.certificate.is_some() || self.identity.is_some() {
+            builder = builder.with_http_client(self.http_client()?);
+        }
+
+        Ok(builder)
+    }
+
+    pub(super) fn http_client(&self) -> anyhow::Result<reqwest::blocking::Client> {
+        let mut client = reqwest::blocking::Client::builder();
+        if let Some(ref certificate) = self.certificate {
+            client = client.add_root_certificate(
+                reqwest::Certificate::from_pem(certificate)
+                    .context("invalid certificate authority")?,
+            );
+        }
+        if let Some((ref cert, ref key)) = self.identity {
+            let mut pem = cert.clone();
+            pem.extend_from_slice(key);
+            client = client
+                .identity(reqwest::Identity::from_pem(&pem).context("invalid client certificate")?);
+        }
+        // the blocking client can't be built from within the async runtime
+        std::thread::spawn(move || client.build())
+            .join()
+            .map_err(|_| anyhow::anyhow!("unable to build http client"))?
+            .context("unable to build http client")
+    }
+}
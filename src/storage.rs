@@ -0,0 +1,429 @@
+//! Measurements kept in a local SQLite database, so that they survive an outage of the
+//! collector.
+//!
+//! The readings of the sensors recorded through the catalogue are written by a dedicated thread.
+//! Once older than the raw retention, measurements are replaced by their hourly minimum,
+//! maximum and average, which are themselves removed after the hourly retention.
+
+// only the collectors record measurements
+#![cfg_attr(not(feature = "bluetooth"), allow(dead_code))]
+
+use std::{
+    path::PathBuf,
+    sync::{
+        Mutex, OnceLock,
+        mpsc::{Receiver, RecvTimeoutError, SyncSender, TrySendError},
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::Context;
+use opentelemetry::KeyValue;
+
+use crate::config::Source;
+
+const DATABASE_NAME: &str = "measurements.sqlite";
+
+/// Measurements waiting to be written, the new ones are dropped when full
+const QUEUE_SIZE: usize = 4096;
+/// Measurements written in a single transaction
+const BATCH_SIZE: usize = 256;
+/// Time between two runs of the downsampling and the retention
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+const HOUR_MILLIS: i64 = 60 * 60 * 1000;
+const DAY_MILLIS: i64 = 24 * HOUR_MILLIS;
+
+static STORAGE: OnceLock<Storage> = OnceLock::new();
+
+#[derive(Debug)]
+pub(crate) struct StorageConfig {
+    enabled: bool,
+    path: Option<PathBuf>,
+    /// Days the measurements are kept before being downsampled
+    raw_retention_days: u32,
+    /// Days the hourly aggregates are kept
+    hourly_retention_days: u32,
+}
+
+impl crate::Configurable for StorageConfig {
+    fn from_source(source: &Source) -> Self {
+        Self {
+            enabled: source
+                .parse("STORAGE_ENABLED", "storage.enabled")
+                .unwrap_or_default(),
+            path: source.parse("STORAGE_PATH", "storage.path"),
+            raw_retention_days: source
+                .parse("STORAGE_RAW_RETENTION_DAYS", "storage.raw_retention_days")
+                .unwrap_or(7),
+            hourly_retention_days: source
+                .parse(
+                    "STORAGE_HOURLY_RETENTION_DAYS",
+                    "storage.hourly_retention_days",
+                )
+                .unwrap_or(365),
+        }
+    }
+}
+
+impl StorageConfig {
+    /// Defaults to the state directory given by systemd
+    fn path(&self) -> PathBuf {
+        self.path
+            .clone()
+            .unwrap_or_else(|| crate::config::state_directory().join(DATABASE_NAME))
+    }
+
+    /// Opens the database and starts the writer, must be called before building the collectors
+    pub(crate) fn install(&self) -> anyhow::Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        let path = self.path();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("unable to create {parent:?}"))?;
+        }
+        let connection = rusqlite::Connection::open(&path)
+            .with_context(|| format!("unable to open database {path:?}"))?;
+        connection
+            .execute_batch(SCHEMA)
+            .with_context(|| format!("unable to prepare database {path:?}"))?;
+
+        let (sender, receiver) = std::sync::mpsc::sync_channel(QUEUE_SIZE);
+        let writer = Writer {
+            connection,
+            raw_retention: i64::from(self.raw_retention_days) * DAY_MILLIS,
+            hourly_retention: i64::from(self.hourly_retention_days) * DAY_MILLIS,
+        };
+        let thread = std::thread::Builder::new()
+            .name("storage".into())
+            .spawn(move || writer.run(receiver))?;
+
+        let meter = opentelemetry::global::meter("storage");
+        let storage = Storage {
+            sender: Mutex::new(Some(sender)),
+            thread: Mutex::new(Some(thread)),
+            dropped: crate::metrics::STORAGE_MEASUREMENTS_DROPPED.u64_counter(&meter),
+        };
+        if STORAGE.set(storage).is_err() {
+            anyhow::bail!("storage already installed");
+        }
+        tracing::info!(message = "storing measurements", path = ?path);
+        Ok(())
+    }
+}
+
+const SCHEMA: &str = "
+PRAGMA journal_mode = WAL;
+CREATE TABLE IF NOT EXISTS measurements (
+    device TEXT NOT NULL,
+    metric TEXT NOT NULL,
+    unit TEXT NOT NULL,
+    value REAL NOT NULL,
+    -- unix timestamp in milliseconds
+    timestamp INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS measurements_timestamp ON measurements (timestamp);
+CREATE TABLE IF NOT EXISTS measurements_hourly (
+    device TEXT NOT NULL,
+    metric TEXT NOT NULL,
+    unit TEXT NOT NULL,
+    -- unix timestamp in milliseconds of the start of the hour
+    hour INTEGER NOT NULL,
+    minimum REAL NOT NULL,
+    maximum REAL NOT NULL,
+    average REAL NOT NULL,
+    count INTEGER NOT NULL,
+    PRIMARY KEY (device, metric, hour)
+);
+";
+
+/// Aggregates the measurements of the full hours before the cutoff
+const DOWNSAMPLE: &str = "
+INSERT INTO measurements_hourly (device, metric, unit, hour, minimum, maximum, average, count)
+SELECT device, metric, unit, timestamp / ?2 * ?2 AS hour,
+    MIN(value), MAX(value), AVG(value), COUNT(*)
+FROM measurements
+WHERE timestamp < ?1
+GROUP BY device, metric, unit, hour
+ON CONFLICT (device, metric, hour) DO UPDATE SET
+    minimum = MIN(minimum, excluded.minimum),
+    maximum = MAX(maximum, excluded.maximum),
+    average = (average * count + excluded.average * excluded.count) / (count + excluded.count),
+    count = count + excluded.count
+";
+
+#[derive(Debug)]
+struct Measurement {
+    device: String,
+    metric: &'static str,
+    unit: &'static str,
+    value: f64,
+    timestamp: i64,
+}
+
+#[derive(Debug)]
+struct Storage {
+    /// Taken when closing so that the writer stops
+    sender: Mutex<Option<SyncSender<Measurement>>>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+    dropped: opentelemetry::metrics::Counter<u64>,
+}
+
+/// Queues a measurement, when the storage is enabled
+pub(crate) fn record(
+    metric: &'static str,
+    unit: &'static str,
+    value: f64,
+    attributes: &[KeyValue],
+) {
+    let Some(storage) = STORAGE.get() else {
+        return;
+    };
+    let Some(device) = device(attributes) else {
+        return;
+    };
+    let measurement = Measurement {
+        device,
+        metric,
+        unit,
+        value,
+        timestamp: unix_millis(SystemTime::now()),
+    };
+    let sender = storage.sender.lock().expect("storage lock poisoned");
+    let Some(ref sender) = *sender else {
+        return;
+    };
+    match sender.try_send(measurement) {
+        Ok(()) => {}
+        Err(TrySendError::Full(_)) => storage.dropped.add(1, &[]),
+        Err(TrySendError::Disconnected(_)) => {
+            storage.dropped.add(1, &[]);
+            tracing::warn!(message = "storage writer stopped, measurement dropped");
+        }
+    }
+}
+
+/// Writes the queued measurements and stops the writer, blocking until it's done
+pub(crate) fn close() {
+    let Some(storage) = STORAGE.get() else {
+        return;
+    };
+    drop(storage.sender.lock().expect("storage lock poisoned").take());
+    let thread = storage.thread.lock().expect("storage lock poisoned").take();
+    if let Some(thread) = thread
+        && thread.join().is_err()
+    {
+        tracing::warn!(message = "storage writer panicked");
+    }
+}
+
+/// Stable key of the device, its identity when resolved
+fn device(attributes: &[KeyValue]) -> Option<String> {
+    ["identity", "address"].into_iter().find_map(|key| {
+        attributes
+            .iter()
+            .find(|kv| kv.key.as_str() == key)
+            .map(|kv| kv.value.to_string())
+    })
+}
+
+fn unix_millis(time: SystemTime) -> i64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as i64)
+        .unwrap_or_default()
+}
+
+struct Writer {
+    connection: rusqlite::Connection,
+    raw_retention: i64,
+    hourly_retention: i64,
+}
+
+impl Writer {
+    fn run(mut self, receiver: Receiver<Measurement>) {
+        let mut next_maintenance = Instant::now();
+        loop {
+            let timeout = next_maintenance.saturating_duration_since(Instant::now());
+            match receiver.recv_timeout(timeout) {
+                Ok(first) => {
+                    let batch = std::iter::once(first)
+                        .chain(receiver.try_iter().take(BATCH_SIZE - 1))
+                        .collect::<Vec<_>>();
+                    if let Err(err) = self.write(&batch) {
+                        tracing::warn!(
+                            message = "unable to store measurements",
+                            count = batch.len(),
+                            error.message = err.to_string(),
+                        );
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+            if Instant::now() >= next_maintenance {
+                if let Err(err) = self.maintain(unix_millis(SystemTime::now())) {
+                    tracing::warn!(
+                        message = "unable to downsample measurements",
+                        error.message = err.to_string(),
+                    );
+                }
+                next_maintenance = Instant::now() + MAINTENANCE_INTERVAL;
+            }
+        }
+    }
+
+    fn write(&mut self, batch: &[Measurement]) -> rusqlite::Result<()> {
+        let transaction = self.connection.transaction()?;
+        {
+            let mut statement = transaction.prepare_cached(
+                "INSERT INTO measurements (device, metric, unit, value, timestamp)
+                VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            for measurement in batch {
+                statement.execute((
+                    &measurement.device,
+                    measurement.metric,
+                    measurement.unit,
+                    measurement.value,
+                    measurement.timestamp,
+                ))?;
+            }
+        }
+        transaction.commit()
+    }
+
+    /// Downsamples the measurements older than the raw retention and removes the old aggregates,
+    /// `now` being a unix timestamp in milliseconds
+    fn maintain(&mut self, now: i64) -> rusqlite::Result<()> {
+        // only full hours are aggregated so that an hour is never split in two rows
+        let cutoff = (now - self.raw_retention) / HOUR_MILLIS * HOUR_MILLIS;
+        let transaction = self.connection.transaction()?;
+        let hours = transaction.execute(DOWNSAMPLE, (cutoff, HOUR_MILLIS))?;
+        let removed =
+            transaction.execute("DELETE FROM measurements WHERE timestamp < ?1", (cutoff,))?;
+        let expired = transaction.execute(
+            "DELETE FROM measurements_hourly WHERE hour < ?1",
+            (now - self.hourly_retention,),
+        )?;
+        transaction.commit()?;
+        tracing::debug!(
+            message = "measurements downsampled",
+            hours = hours,
+            removed = removed,
+            expired = expired,
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn writer() -> Writer {
+        let connection = rusqlite::Connection::open_in_memory().unwrap();
+        connection.execute_batch(SCHEMA).unwrap();
+        Writer {
+            connection,
+            raw_retention: HOUR_MILLIS,
+            hourly_retention: 24 * HOUR_MILLIS,
+        }
+    }
+
+    fn measurement(value: f64, timestamp: i64) -> Measurement {
+        Measurement {
+            device: "kitchen".into(),
+            metric: "sensor.temperature",
+            unit: "Cel",
+            value,
+            timestamp,
+        }
+    }
+
+    fn hourly(writer: &Writer) -> Vec<(i64, f64, f64, f64, i64)> {
+        let mut statement = writer
+            .connection
+            .prepare(
+                "SELECT hour, minimum, maximum, average, count
+                FROM measurements_hourly ORDER BY hour",
+            )
+            .unwrap();
+        statement
+            .query_map((), |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                ))
+            })
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap()
+    }
+
+    fn raw(writer: &Writer) -> i64 {
+        writer
+            .connection
+            .query_row("SELECT COUNT(*) FROM measurements", (), |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn downsamples_the_full_hours_before_the_raw_retention() {
+        let mut writer = writer();
+        writer
+            .write(&[
+                measurement(20.0, 1000),
+                measurement(22.0, HOUR_MILLIS - 1),
+                measurement(30.0, HOUR_MILLIS),
+            ])
+            .unwrap();
+        // the second hour isn't over the retention yet
+        writer.maintain(2 * HOUR_MILLIS + 1000).unwrap();
+        assert_eq!(hourly(&writer), [(0, 20.0, 22.0, 21.0, 2)]);
+        assert_eq!(raw(&writer), 1);
+    }
+
+    #[test]
+    fn merges_the_late_measurements_into_the_existing_hour() {
+        let mut writer = writer();
+        writer
+            .write(&[measurement(20.0, 1000), measurement(22.0, 2000)])
+            .unwrap();
+        writer.maintain(2 * HOUR_MILLIS).unwrap();
+        // written after the hour was aggregated, like a measurement stuck in the queue
+        writer.write(&[measurement(14.0, 3000)]).unwrap();
+        writer.maintain(2 * HOUR_MILLIS).unwrap();
+        assert_eq!(hourly(&writer), [(0, 14.0, 22.0, 56.0 / 3.0, 3)]);
+        assert_eq!(raw(&writer), 0);
+    }
+
+    #[test]
+    fn removes_the_aggregates_after_the_hourly_retention() {
+        let mut writer = writer();
+        writer
+            .write(&[measurement(20.0, 1000), measurement(21.0, HOUR_MILLIS)])
+            .unwrap();
+        writer.maintain(3 * HOUR_MILLIS).unwrap();
+        assert_eq!(hourly(&writer).len(), 2);
+        writer.maintain(25 * HOUR_MILLIS).unwrap();
+        assert_eq!(hourly(&writer), [(HOUR_MILLIS, 21.0, 21.0, 21.0, 1)]);
+    }
+
+    #[test]
+    fn keys_the_measurements_by_device() {
+        let address = KeyValue::new("address", "A4:C1:38:00:00:01");
+        let identity = KeyValue::new("identity", "phone");
+        assert_eq!(
+            device(&[address.clone(), identity]),
+            Some("phone".to_string())
+        );
+        assert_eq!(device(&[address]), Some("A4:C1:38:00:00:01".to_string()));
+        assert_eq!(device(&[KeyValue::new("task", "reloader")]), None);
+    }
+}
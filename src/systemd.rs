@@ -0,0 +1,41 @@
+//! Notifications sent to systemd, they are ignored when not running as a `Type=notify` service.
+
+// only the collectors report their progress
+#![cfg_attr(not(feature = "bluetooth"), allow(dead_code))]
+
+use std::time::Duration;
+
+use sd_notify::NotifyState;
+
+fn notify(state: &[NotifyState]) {
+    if let Err(err) = sd_notify::notify(false, state) {
+        tracing::warn!(
+            message = "unable to notify systemd",
+            error.message = err.to_string(),
+        );
+    }
+}
+
+/// Every collector has started
+pub(crate) fn ready() {
+    notify(&[NotifyState::Ready]);
+}
+
+pub(crate) fn stopping() {
+    notify(&[NotifyState::Stopping]);
+}
+
+/// Line displayed by `systemctl status`
+pub(crate) fn status(status: &str) {
+    notify(&[NotifyState::Status(status)]);
+}
+
+pub(crate) fn watchdog() {
+    notify(&[NotifyState::Watchdog]);
+}
+
+/// Time between two pings, half of `WatchdogSec` like recommended by systemd
+pub(crate) fn watchdog_interval() -> Option<Duration> {
+    let mut usec = 0;
+    sd_notify::watchdog_enabled(false, &mut usec).then(|| Duration::from_micros(usec) / 2)
+}